@@ -1,16 +1,27 @@
 use anyhow::Context;
+use duck_duck_go_ai::model::ChatResponseMessage;
 use duck_duck_go_ai::ChatMessage;
 use duck_duck_go_ai::ChatRequest;
 use duck_duck_go_ai::Client;
+use duck_duck_go_ai::Role;
 use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
 use pyo3::exceptions::PyIndexError;
+use pyo3::exceptions::PyStopAsyncIteration;
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
+use pyo3::types::PyIterator;
+use pyo3::types::PyList;
 use pyo3::types::PyString;
+use std::path::PathBuf;
 use std::sync::Arc;
+use tokio::sync::mpsc::Receiver;
 use tokio::sync::Mutex;
 use tokio::sync::MutexGuard;
 use tokio::sync::OwnedMutexGuard;
-use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 
 static TOKIO_RUNTIME: Lazy<std::io::Result<tokio::runtime::Runtime>> = Lazy::new(|| {
     tokio::runtime::Builder::new_multi_thread()
@@ -20,6 +31,169 @@ static TOKIO_RUNTIME: Lazy<std::io::Result<tokio::runtime::Runtime>> = Lazy::new
 
 static CLIENT: Lazy<Client> = Lazy::new(Client::new);
 
+static AVAILABLE_MODELS: OnceCell<Vec<String>> = OnceCell::new();
+
+create_exception!(
+    duck_duck_go_ai_py,
+    DdgError,
+    PyException,
+    "An error from Duck Duck Go's AI chat."
+);
+create_exception!(
+    duck_duck_go_ai_py,
+    DdgRateLimitError,
+    DdgError,
+    "The server rate limited the request."
+);
+create_exception!(
+    duck_duck_go_ai_py,
+    DdgVqdError,
+    DdgError,
+    "The vqd token was missing or rejected."
+);
+create_exception!(
+    duck_duck_go_ai_py,
+    DdgHttpError,
+    DdgError,
+    "A network or http error occured."
+);
+create_exception!(
+    duck_duck_go_ai_py,
+    DdgModelError,
+    DdgError,
+    "The model is retired, or a different model was served."
+);
+
+/// The default number of tokens buffered before the response stream waits for the consumer.
+const DEFAULT_CHANNEL_CAPACITY: usize = 64;
+
+/// Get the models that are currently supported.
+///
+/// These are only fetched once.
+fn get_available_models() -> PyResult<&'static Vec<String>> {
+    AVAILABLE_MODELS.get_or_try_init(|| {
+        let tokio_rt = TOKIO_RUNTIME
+            .as_ref()
+            .context("failed to init tokio runtime")?;
+
+        tokio_rt
+            .block_on(CLIENT.list_models())
+            .map_err(|error| to_py_err(error, "failed to list models"))
+    })
+}
+
+/// Convert a library error into the matching Python exception.
+fn to_py_err(error: duck_duck_go_ai::Error, context: &'static str) -> PyErr {
+    use duck_duck_go_ai::Error;
+
+    let cause = match &error {
+        Error::PartialResponse { source, .. } => source.as_ref(),
+        error => error,
+    };
+    let new_err: fn(String) -> PyErr = match cause {
+        Error::RateLimited { .. } => DdgRateLimitError::new_err,
+        Error::MissingVqd | Error::InvalidVqd(_) => DdgVqdError::new_err,
+        Error::Http { body, .. } if body.contains("ERR_INVALID_VQD") => DdgVqdError::new_err,
+        Error::Http { .. }
+        | Error::UnexpectedContentType { .. }
+        | Error::Reqwest(_)
+        | Error::Connect(_)
+        | Error::Decode(_) => DdgHttpError::new_err,
+        Error::RetiredModel(_) | Error::ModelMismatch { .. } => DdgModelError::new_err,
+        _ => DdgError::new_err,
+    };
+
+    new_err(format!("{:#}", anyhow::Error::from(error).context(context)))
+}
+
+/// A guard for a chat request with a pending user message.
+///
+/// If dropped before a response is added, the user message is removed.
+struct ChatRequestGuard {
+    chat_request: OwnedMutexGuard<ChatRequest>,
+    pop_last: bool,
+}
+
+impl ChatRequestGuard {
+    fn new(chat_request: OwnedMutexGuard<ChatRequest>) -> Self {
+        Self {
+            chat_request,
+            pop_last: true,
+        }
+    }
+
+    /// Add the response message from the server, diffusing the drop guard.
+    fn push_response(&mut self, response: ChatMessage) {
+        assert!(self.pop_last);
+
+        self.chat_request.messages.push(response);
+        self.pop_last = false;
+    }
+}
+
+impl Drop for ChatRequestGuard {
+    fn drop(&mut self) {
+        if self.pop_last {
+            self.chat_request.messages.pop();
+        }
+    }
+}
+
+/// Send the chat request, streaming the response tokens in the background.
+///
+/// At most `capacity` tokens are buffered,
+/// after which the background task waits for the consumer.
+/// The response is added to the chat request once it is complete.
+/// If the token is cancelled, the http connection is dropped and the response is discarded.
+async fn stream_response(
+    mut chat_request: ChatRequestGuard,
+    capacity: usize,
+    cancel: CancellationToken,
+) -> PyResult<Receiver<Result<ChatResponseMessage, duck_duck_go_ai::Error>>> {
+    let stream = CLIENT
+        .chat(&chat_request.chat_request)
+        .await
+        .map_err(|error| to_py_err(error, "failed to send chat request"))?
+        .with_cancellation(cancel);
+    if let Some(vqd) = stream.vqd() {
+        chat_request.chat_request.set_vqd(vqd.into());
+    }
+    if let Some(vqd_hash) = stream.vqd_hash() {
+        chat_request.chat_request.set_vqd_hash(vqd_hash.into());
+    }
+
+    let rx = stream.into_channel(capacity, move |message| {
+        // A failed or aborted response is dropped along with the user message.
+        if let Some(message) = message {
+            chat_request.push_response(message);
+        }
+    });
+
+    Ok(rx)
+}
+
+/// Convert an error from a response stream into a Python exception.
+fn stream_error(error: duck_duck_go_ai::Error) -> PyErr {
+    to_py_err(error, "failed to stream response")
+}
+
+/// Ensure that a channel capacity is valid.
+fn check_capacity(capacity: usize) -> PyResult<()> {
+    if capacity == 0 {
+        return Err(PyValueError::new_err("capacity must be greater than 0"));
+    }
+
+    Ok(())
+}
+
+/// A saved chat, with the vqd that the request does not serialize.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Session {
+    request: ChatRequest,
+    vqd: Option<String>,
+    vqd_hash: Option<String>,
+}
+
 /// A chat with an AI
 #[pyclass(sequence)]
 struct Chat {
@@ -31,6 +205,20 @@ impl Chat {
     fn get_chat_request(&self) -> Option<MutexGuard<'_, ChatRequest>> {
         self.chat_request.try_lock().ok()
     }
+
+    /// Lock the chat request and add a user message to it.
+    fn push_user_message(&self, content: &str) -> PyResult<ChatRequestGuard> {
+        let mut chat_request = self
+            .chat_request
+            .clone()
+            .try_lock_owned()
+            .ok()
+            .context("chat is busy")?;
+
+        chat_request.messages.push(ChatMessage::user(content));
+
+        Ok(ChatRequestGuard::new(chat_request))
+    }
 }
 
 #[pymethods]
@@ -44,7 +232,66 @@ impl Chat {
 
         let chat_request = tokio_rt
             .block_on(CLIENT.init_chat())
-            .context("failed to init chat")?;
+            .map_err(|error| to_py_err(error, "failed to init chat"))?;
+
+        Ok(Self {
+            chat_request: Arc::new(Mutex::new(chat_request)),
+        })
+    }
+
+    /// Save the messages, model, and vqd of this chat to a file.
+    ///
+    /// Use `Chat.load_session` to resume it without a new handshake.
+    pub fn save_session(&self, path: PathBuf) -> PyResult<()> {
+        let chat_request = self.get_chat_request().context("chat is busy")?;
+        let session = Session {
+            request: chat_request.clone(),
+            vqd: chat_request.vqd().map(String::from),
+            vqd_hash: chat_request.vqd_hash().map(String::from),
+        };
+        drop(chat_request);
+
+        let json = serde_json::to_vec_pretty(&session).context("failed to serialize session")?;
+        std::fs::write(path, json)?;
+
+        Ok(())
+    }
+
+    /// Load a chat saved with `Chat.save_session`.
+    ///
+    /// The saved vqd is reused, so no handshake is needed.
+    /// If it has expired, it is refreshed automatically on the next message.
+    /// Pass `refresh=True` to fetch a new vqd now instead.
+    #[staticmethod]
+    #[pyo3(signature = (path, refresh = false))]
+    pub fn load_session(path: PathBuf, refresh: bool) -> PyResult<Self> {
+        let json = std::fs::read(path)?;
+        let session: Session = serde_json::from_slice(&json)
+            .map_err(|error| PyValueError::new_err(format!("invalid session: {error}")))?;
+
+        let mut chat_request = session.request;
+        match session.vqd {
+            Some(vqd) if !refresh => {
+                chat_request.set_vqd(vqd);
+                if let Some(vqd_hash) = session.vqd_hash {
+                    chat_request.set_vqd_hash(vqd_hash);
+                }
+            }
+            _ => {
+                let tokio_rt = TOKIO_RUNTIME
+                    .as_ref()
+                    .context("failed to init tokio runtime")?;
+                let fresh = tokio_rt
+                    .block_on(CLIENT.resume_chat(Vec::new()))
+                    .map_err(|error| to_py_err(error, "failed to refresh vqd"))?;
+                if let Some(vqd) = fresh.vqd() {
+                    chat_request.set_vqd(vqd.into());
+                }
+                if let Some(vqd_hash) = fresh.vqd_hash() {
+                    chat_request.set_vqd_hash(vqd_hash.into());
+                }
+            }
+        }
 
         Ok(Self {
             chat_request: Arc::new(Mutex::new(chat_request)),
@@ -57,16 +304,31 @@ impl Chat {
         Ok(PyString::new_bound(py, chat_request.model.as_str()))
     }
 
+    /// Get the models that are currently supported.
+    #[staticmethod]
+    pub fn available_models() -> PyResult<Vec<String>> {
+        Ok(get_available_models()?.clone())
+    }
+
     /// Set the model.
+    ///
+    /// The model must be one of the available models.
+    /// It can be changed between turns, but not while a response is streaming.
     pub fn set_model(&mut self, model: &str) -> PyResult<()> {
-        let mut chat_request = self.get_chat_request().context("chat is busy")?;
-        if chat_request.messages.len() >= 2 {
-            return Err(Into::into(anyhow::Error::msg(
-                "cannot change model of in-progress chat",
-            )));
+        if !get_available_models()?
+            .iter()
+            .any(|available_model| available_model == model)
+        {
+            return Err(PyValueError::new_err(format!("unknown model \"{model}\"")));
         }
 
+        // A streaming response holds the lock until it is done,
+        // so it is always sent and recorded with a single model.
+        let mut chat_request = self
+            .get_chat_request()
+            .context("cannot change model while a response is streaming")?;
         chat_request.model = model.into();
+
         Ok(())
     }
 
@@ -108,119 +370,95 @@ impl Chat {
         Ok(Some((role, content)))
     }
 
-    /// Create a user message and get the response.
-    pub fn send_message(&self, content: &str) -> PyResult<ChatResponseStream> {
-        struct ChatRequestGuard {
-            chat_request: OwnedMutexGuard<ChatRequest>,
-            pop_last: bool,
-        }
+    /// Iterate over the (role, content) pairs of the chat history.
+    ///
+    /// The history is copied when iteration starts,
+    /// so later messages do not change what is iterated.
+    pub fn __iter__<'a>(&self, py: Python<'a>) -> PyResult<Bound<'a, PyIterator>> {
+        let chat_request = self.get_chat_request().context("chat is busy")?;
+        let messages = PyList::new_bound(
+            py,
+            chat_request
+                .messages
+                .iter()
+                .map(|message| (message.role.as_str(), message.content.as_str())),
+        );
+        drop(chat_request);
+
+        messages.as_any().iter()
+    }
 
-        impl ChatRequestGuard {
-            fn new(chat_request: OwnedMutexGuard<ChatRequest>) -> Self {
-                Self {
-                    chat_request,
-                    pop_last: true,
-                }
-            }
+    /// Clear the chat history, keeping the vqd.
+    pub fn reset(&self) -> PyResult<()> {
+        let mut chat_request = self.get_chat_request().context("chat is busy")?;
+        chat_request.messages.clear();
+        Ok(())
+    }
 
-            /// Add the response message from the server, diffusing the drop guard.
-            fn push_response(&mut self, response: ChatMessage) {
-                assert!(self.pop_last);
+    /// Remove the last user/assistant message pair.
+    pub fn pop(&self) -> PyResult<()> {
+        let mut chat_request = self.get_chat_request().context("chat is busy")?;
 
-                self.chat_request.messages.push(response);
-                self.pop_last = false;
-            }
+        let last = chat_request
+            .messages
+            .pop()
+            .ok_or_else(|| PyIndexError::new_err("pop from empty chat"))?;
+        if last.role == Role::Assistant
+            && chat_request
+                .messages
+                .last()
+                .is_some_and(|message| message.role == Role::User)
+        {
+            chat_request.messages.pop();
         }
 
-        impl Drop for ChatRequestGuard {
-            fn drop(&mut self) {
-                if self.pop_last {
-                    self.chat_request.messages.pop();
-                }
-            }
-        }
+        Ok(())
+    }
 
+    /// Create a user message and get the response.
+    ///
+    /// At most `capacity` tokens are buffered before the response waits to be consumed.
+    #[pyo3(signature = (content, capacity = DEFAULT_CHANNEL_CAPACITY))]
+    pub fn send_message(&self, content: &str, capacity: usize) -> PyResult<ChatResponseStream> {
         let tokio_rt = TOKIO_RUNTIME
             .as_ref()
             .context("failed to init tokio runtime")?;
 
-        let mut chat_request = self
-            .chat_request
-            .clone()
-            .try_lock_owned()
-            .ok()
-            .context("chat is busy")?;
-
-        chat_request.messages.push(ChatMessage {
-            role: "user".into(),
-            content: content.into(),
-        });
-
-        let mut chat_request = ChatRequestGuard::new(chat_request);
-
-        let rx = tokio_rt.block_on(async move {
-            let mut stream = CLIENT
-                .chat(&chat_request.chat_request)
-                .await
-                .context("failed to send chat request")?;
-
-            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-            tokio::spawn(async move {
-                let mut role = None;
-                let mut content = String::new();
-                let mut stream_error = None;
-
-                while let Some(message_result) = stream.next().await {
-                    let message_result = message_result.context("failed to parse event");
-                    match message_result {
-                        Ok(message) => {
-                            if let Some(message_role) = message.role {
-                                role = Some(message_role);
-                            }
-                            if let Some(message) = message.message {
-                                content.push_str(&message);
-
-                                // We need to keep processing even if we got cancelled.
-                                let _ = tx.send(Ok(message)).is_ok();
-                            }
-                        }
-                        Err(error) => {
-                            // The error might be fatal.
-                            // We are forced to exit here.
-                            // Note that an error here means the user
-                            // should recreate the entire chat from scratch.
-                            stream_error = Some(error);
-                            break;
-                        }
-                    }
-                }
-
-                match stream_error {
-                    Some(error) => {
-                        // Doesn't matter if nobody cares that we failed.
-                        let _ = tx.send(Err(error)).is_ok();
-                        drop(chat_request);
-                    }
-                    None => {
-                        match role.context("missing role") {
-                            Ok(role) => {
-                                let new_message = ChatMessage { role, content };
-                                chat_request.push_response(new_message);
-                                drop(chat_request);
-                            }
-                            Err(error) => {
-                                // Doesn't matter if nobody cares that we failed.
-                                let _ = tx.send(Err(error)).is_ok();
-                            }
-                        };
-                    }
-                }
-            });
+        check_capacity(capacity)?;
+        let chat_request = self.push_user_message(content)?;
+        let cancel = CancellationToken::new();
+        let rx = tokio_rt.block_on(stream_response(chat_request, capacity, cancel.clone()))?;
 
-            anyhow::Ok(rx)
-        })?;
+        Ok(ChatResponseStream {
+            rx,
+            cancel,
+            metadata: TokenMetadata::default(),
+        })
+    }
 
-        Ok(ChatResponseStream { rx })
+    /// Create a user message and get the response, asynchronously.
+    ///
+    /// This returns an awaitable, which resolves to an async iterator of tokens.
+    /// At most `capacity` tokens are buffered before the response waits to be consumed.
+    #[pyo3(signature = (content, capacity = DEFAULT_CHANNEL_CAPACITY))]
+    pub fn send_message_async<'a>(
+        &self,
+        py: Python<'a>,
+        content: &str,
+        capacity: usize,
+    ) -> PyResult<Bound<'a, PyAny>> {
+        check_capacity(capacity)?;
+        let chat_request = self.push_user_message(content)?;
+
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let cancel = CancellationToken::new();
+            let rx = stream_response(chat_request, capacity, cancel.clone()).await?;
+            Ok(AsyncChatResponseStream {
+                rx: Arc::new(Mutex::new(rx)),
+                cancel,
+                metadata: Arc::default(),
+            })
+        })
     }
 
     pub fn __str__(&self) -> String {
@@ -229,7 +467,8 @@ impl Chat {
             Some(chat_request) => {
                 format!(
                     "Chat(messages={:?}, model={:?})",
-                    chat_request.messages, chat_request.model
+                    chat_request.messages,
+                    chat_request.model.as_str()
                 )
             }
             None => "Chat(<chat is busy>)".to_string(),
@@ -237,34 +476,169 @@ impl Chat {
     }
 }
 
+/// Metadata from the tokens of a response.
+#[derive(Debug, Default)]
+struct TokenMetadata {
+    /// The model that served the response
+    model: Option<String>,
+
+    /// The id of the last token
+    id: Option<String>,
+}
+
+impl TokenMetadata {
+    /// Record the metadata of a response message, returning its content.
+    fn update(&mut self, message: ChatResponseMessage) -> String {
+        self.model = Some(message.model);
+        self.id = Some(message.id);
+
+        message.message.unwrap_or_default()
+    }
+}
+
 /// A streaming chat response.
+///
+/// Dropping this before it is exhausted aborts the response.
 #[pyclass]
 pub struct ChatResponseStream {
-    rx: tokio::sync::mpsc::UnboundedReceiver<anyhow::Result<String>>,
+    rx: Receiver<Result<ChatResponseMessage, duck_duck_go_ai::Error>>,
+    cancel: CancellationToken,
+    metadata: TokenMetadata,
 }
 
 #[pymethods]
 impl ChatResponseStream {
+    /// Abort the response, dropping the http connection.
+    ///
+    /// The user message and partial response are not added to the chat.
+    fn close(&mut self) {
+        self.cancel.cancel();
+        self.rx.close();
+    }
+
     fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
         slf
     }
 
+    /// The model that served the response, once a token was received.
+    #[getter]
+    fn model(&self) -> Option<String> {
+        self.metadata.model.clone()
+    }
+
+    /// The id of the last received token.
+    #[getter]
+    fn id(&self) -> Option<String> {
+        self.metadata.id.clone()
+    }
+
     fn __next__<'a>(
         mut slf: PyRefMut<'_, Self>,
         py: Python<'a>,
     ) -> PyResult<Option<Bound<'a, PyString>>> {
-        slf.rx
-            .blocking_recv()
-            .transpose()
-            .map(|token| token.map(|token| PyString::new_bound(py, &token)))
-            .map_err(Into::into)
+        let message = match slf.rx.blocking_recv().transpose().map_err(stream_error)? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        let token = slf.metadata.update(message);
+
+        Ok(Some(PyString::new_bound(py, &token)))
+    }
+}
+
+impl Drop for ChatResponseStream {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+/// An asynchronous streaming chat response.
+///
+/// Dropping this before it is exhausted aborts the response.
+#[pyclass]
+pub struct AsyncChatResponseStream {
+    rx: Arc<Mutex<Receiver<Result<ChatResponseMessage, duck_duck_go_ai::Error>>>>,
+    cancel: CancellationToken,
+    metadata: Arc<std::sync::Mutex<TokenMetadata>>,
+}
+
+#[pymethods]
+impl AsyncChatResponseStream {
+    /// Abort the response, dropping the http connection.
+    ///
+    /// The user message and partial response are not added to the chat.
+    fn close(&self) {
+        self.cancel.cancel();
+        if let Ok(mut rx) = self.rx.try_lock() {
+            rx.close();
+        }
+    }
+
+    /// The model that served the response, once a token was received.
+    #[getter]
+    fn model(&self) -> Option<String> {
+        self.lock_metadata().model.clone()
+    }
+
+    /// The id of the last received token.
+    #[getter]
+    fn id(&self) -> Option<String> {
+        self.lock_metadata().id.clone()
+    }
+
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'a>(&self, py: Python<'a>) -> PyResult<Bound<'a, PyAny>> {
+        let rx = self.rx.clone();
+        let metadata = self.metadata.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let mut rx = rx.lock().await;
+            match rx.recv().await {
+                Some(Ok(message)) => Ok(metadata
+                    .lock()
+                    .unwrap_or_else(|error| error.into_inner())
+                    .update(message)),
+                Some(Err(error)) => Err(stream_error(error)),
+                None => Err(PyStopAsyncIteration::new_err(())),
+            }
+        })
+    }
+}
+
+impl AsyncChatResponseStream {
+    fn lock_metadata(&self) -> std::sync::MutexGuard<'_, TokenMetadata> {
+        self.metadata
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl Drop for AsyncChatResponseStream {
+    fn drop(&mut self) {
+        self.cancel.cancel();
     }
 }
 
 /// A pyo3 module for Duck Duck Go's AI chat.
 #[pymodule]
 fn duck_duck_go_ai_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    // Share our runtime with async methods.
+    if let Ok(tokio_rt) = TOKIO_RUNTIME.as_ref() {
+        let _ = pyo3_async_runtimes::tokio::init_with_runtime(tokio_rt).is_ok();
+    }
+
     m.add_class::<Chat>()?;
     m.add_class::<ChatResponseStream>()?;
+    m.add_class::<AsyncChatResponseStream>()?;
+    m.add("DdgError", m.py().get_type_bound::<DdgError>())?;
+    m.add(
+        "DdgRateLimitError",
+        m.py().get_type_bound::<DdgRateLimitError>(),
+    )?;
+    m.add("DdgVqdError", m.py().get_type_bound::<DdgVqdError>())?;
+    m.add("DdgHttpError", m.py().get_type_bound::<DdgHttpError>())?;
+    m.add("DdgModelError", m.py().get_type_bound::<DdgModelError>())?;
     Ok(())
 }
@@ -0,0 +1,177 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::measurement::Measurement;
+use criterion::measurement::ValueFormatter;
+use criterion::BatchSize;
+use criterion::Criterion;
+use criterion::Throughput;
+use duck_duck_go_ai::ChatResponseStream;
+use duck_duck_go_ai::Model;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// The number of allocations made so far, including reallocations.
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting allocations.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// A criterion measurement of the number of allocations.
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> Self::Intermediate {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: Self::Intermediate) -> Self::Value {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        v1 + v2
+    }
+
+    fn zero(&self) -> Self::Value {
+        0
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationsFormatter
+    }
+}
+
+/// Formats allocation counts, per response or per event.
+struct AllocationsFormatter;
+
+impl ValueFormatter for AllocationsFormatter {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        let (elements, unit) = match *throughput {
+            Throughput::Elements(elements) => (elements, "allocs/event"),
+            Throughput::Bytes(bytes) | Throughput::BytesDecimal(bytes) => (bytes, "allocs/byte"),
+        };
+        for value in values {
+            *value /= elements as f64;
+        }
+
+        unit
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+/// Make a transcript like a real response, with one event per token.
+fn make_transcript(tokens: usize) -> Vec<u8> {
+    let mut transcript = String::new();
+    for i in 0..tokens {
+        let message = serde_json::json!({
+            "role": "assistant",
+            "message": format!(" token{i}"),
+            "created": 1731000000,
+            "id": "chatcmpl-AS1DyoseGwN3yo4MiUo1YRPDJCtIg",
+            "action": "success",
+            "model": "gpt-4o-mini-2024-07-18",
+        });
+        transcript.push_str(&format!("data: {message}\n\n"));
+    }
+    transcript.push_str("data: [DONE]\n\n");
+
+    transcript.into_bytes()
+}
+
+/// Benchmark collecting transcripts into messages, with the given throughput for a transcript.
+fn collect_with<M, T>(c: &mut Criterion<M>, name: &str, throughput: T)
+where
+    M: Measurement,
+    T: Fn(usize, &[u8]) -> Throughput,
+{
+    let tokio_rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("failed to build tokio runtime");
+
+    let mut group = c.benchmark_group(name);
+    for tokens in [10, 1000] {
+        let transcript = make_transcript(tokens);
+        group.throughput(throughput(tokens, &transcript));
+        group.bench_function(tokens.to_string(), |b| {
+            b.iter_batched(
+                || {
+                    let reader = std::io::Cursor::new(transcript.clone());
+                    ChatResponseStream::from_reader(reader, Model::Gpt4OMini)
+                },
+                |mut stream| {
+                    tokio_rt
+                        .block_on(stream.collect_into_chat_message())
+                        .expect("failed to collect message")
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+fn collect(c: &mut Criterion) {
+    collect_with(c, "collect_into_chat_message", |_, transcript| {
+        Throughput::Bytes(transcript.len() as u64)
+    });
+}
+
+fn collect_allocations(c: &mut Criterion<Allocations>) {
+    collect_with(c, "collect_into_chat_message_allocations", |tokens, _| {
+        Throughput::Elements(tokens as u64)
+    });
+}
+
+criterion_group!(benches, collect);
+criterion_group! {
+    name = allocations;
+    config = Criterion::default().with_measurement(Allocations);
+    targets = collect_allocations
+}
+criterion_main!(benches, allocations);
@@ -0,0 +1,123 @@
+//! A blocking client.
+//!
+//! This wraps the async [`Client`](crate::Client) with a small runtime,
+//! like reqwest's blocking client does.
+//! These methods must not be called from within an async runtime, or they will panic.
+
+use crate::model::ChatResponseMessage;
+use crate::ChatMessage;
+use crate::ChatRequest;
+use crate::Error;
+use crate::Model;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio_stream::StreamExt;
+
+/// A blocking client.
+///
+/// This is cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Client {
+    inner: crate::Client,
+    runtime: Arc<Runtime>,
+}
+
+impl Client {
+    /// Make a new blocking client with the default settings.
+    pub fn new() -> Result<Self, Error> {
+        Self::from_async(crate::Client::new())
+    }
+
+    /// Make a new blocking client from an async client.
+    ///
+    /// Use this with a [`ClientBuilder`](crate::ClientBuilder) to customize the client.
+    pub fn from_async(inner: crate::Client) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        Ok(Self {
+            inner,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Get the async client.
+    pub fn as_async(&self) -> &crate::Client {
+        &self.inner
+    }
+
+    /// Init a new chat.
+    pub fn init_chat(&self) -> Result<ChatRequest, Error> {
+        self.runtime.block_on(self.inner.init_chat())
+    }
+
+    /// Init a new chat with the given model.
+    pub fn init_chat_with_model(&self, model: impl Into<Model>) -> Result<ChatRequest, Error> {
+        self.runtime
+            .block_on(self.inner.init_chat_with_model(model))
+    }
+
+    /// List the models that can currently be used.
+    pub fn list_models(&self) -> Result<Vec<String>, Error> {
+        self.runtime.block_on(self.inner.list_models())
+    }
+
+    /// Send a chat request, returning an iterator over the response.
+    pub fn chat(&self, request: &ChatRequest) -> Result<ChatResponseStream, Error> {
+        let inner = self.runtime.block_on(self.inner.chat(request))?;
+
+        Ok(ChatResponseStream {
+            inner,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// Send a chat request and collect the response.
+    pub fn chat_once(&self, request: &ChatRequest) -> Result<ChatMessage, Error> {
+        self.runtime.block_on(self.inner.chat_once(request))
+    }
+
+    /// Send a chat request, collect the response, and append it to the request.
+    pub fn chat_and_append(&self, request: &mut ChatRequest) -> Result<ChatMessage, Error> {
+        self.runtime.block_on(self.inner.chat_and_append(request))
+    }
+}
+
+/// A blocking iterator over a chat response.
+#[derive(Debug)]
+pub struct ChatResponseStream {
+    inner: crate::ChatResponseStream,
+    runtime: Arc<Runtime>,
+}
+
+impl ChatResponseStream {
+    /// Get the model that served the response, if a message has been received.
+    pub fn served_model(&self) -> Option<&str> {
+        self.inner.served_model()
+    }
+
+    /// Check whether the response finished normally.
+    pub fn is_complete(&self) -> bool {
+        self.inner.is_complete()
+    }
+
+    /// Collect the rest of the response into a chat message.
+    pub fn collect_into_chat_message(&mut self) -> Result<ChatMessage, Error> {
+        self.runtime
+            .block_on(self.inner.collect_into_chat_message())
+    }
+
+    /// Get the async stream.
+    pub fn into_async(self) -> crate::ChatResponseStream {
+        self.inner
+    }
+}
+
+impl Iterator for ChatResponseStream {
+    type Item = Result<ChatResponseMessage, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runtime.block_on(self.inner.next())
+    }
+}
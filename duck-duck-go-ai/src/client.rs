@@ -1,77 +1,1094 @@
+use crate::model::ContentFilter;
+use crate::model::DEFAULT_MODEL;
+use crate::sse::SseStream;
+#[cfg(feature = "replay")]
+use crate::transport::RecordingTransport;
+#[cfg(feature = "replay")]
+use crate::transport::ReplayTransport;
+use crate::transport::ReqwestTransport;
+use crate::transport::Transport;
+use crate::transport::TransportFuture;
+use crate::ChatMessage;
 use crate::ChatRequest;
 use crate::ChatResponseStream;
 use crate::Error;
-use futures_util::stream::TryStreamExt;
-use nd_tokio_sse_codec::SseCodec;
-use tokio_util::codec::FramedRead;
-use tokio_util::io::StreamReader;
+use crate::FilterAction;
+use crate::Model;
+use crate::Observer;
+use crate::StatusInfo;
+use futures_util::future::join_all;
+#[cfg(not(target_arch = "wasm32"))]
+use reqwest::cookie::CookieStore;
+#[cfg(not(target_arch = "wasm32"))]
+use reqwest::cookie::Jar;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderName;
+use reqwest::header::HeaderValue;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use std::borrow::Cow;
+use std::future::Future;
+#[cfg(feature = "replay")]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio_stream::Stream;
+use tokio_util::sync::CancellationToken;
 
+#[cfg(not(target_arch = "wasm32"))]
 static USER_AGENT_STR: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/128.0.0.0 Safari/537.36";
-const DEFAULT_MODEL: &str = "gpt-4o-mini";
-const CHAT_URL: &str = "https://duckduckgo.com/duckchat/v1/chat";
+const DEFAULT_BASE_URL: &str = "https://duckduckgo.com";
+const STATUS_PATH: &str = "/duckchat/v1/status";
+const CHAT_PATH: &str = "/duckchat/v1/chat";
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const VQD_HASH_HEADER: &str = "x-vqd-hash-1";
+const MAX_RESUMES: u32 = 3;
+const MIN_RESUME_OVERLAP: usize = 8;
+const MAX_RESUME_OVERLAP: usize = 1024;
+const BODY_SNIPPET_LEN: usize = 512;
+const MIN_RESET_TIMESTAMP: u64 = 1_000_000_000;
+const RESUME_PROMPT: &str =
+    "Continue your last message exactly where it left off, without repeating any of it.";
 
 /// A client for duck duck go's ai features.
+///
+/// A client holds no per-chat state, so it can be shared to run many chats concurrently.
+/// Cloning a client is cheap, and clones share the same connection pool.
 #[derive(Debug, Clone)]
 pub struct Client {
     /// The inner http client
     pub client: reqwest::Client,
+
+    /// The transport that requests are sent with
+    transport: Arc<dyn Transport>,
+
+    /// The number of times to retry rate-limited requests
+    max_retries: u32,
+
+    /// The base url, without a trailing slash
+    base_url: String,
+
+    /// The model for new chats
+    default_model: Model,
+
+    /// Headers sent with every request
+    default_headers: HeaderMap,
+
+    /// The "x-vqd-hash-1" challenge solver
+    vqd_hash_solver: Option<VqdHashSolver>,
+
+    /// A function that customizes each request before it is sent
+    request_middleware: Option<RequestMiddleware>,
+
+    /// The timer used to wait before retrying rate-limited requests
+    sleep: Option<SleepFn>,
+
+    /// The observer for requests, tokens, and errors
+    observer: Option<Arc<dyn Observer>>,
+
+    /// Whether to resume responses that are cut off by a dropped connection
+    resume_on_disconnect: bool,
+
+    /// The timeout for status handshakes
+    handshake_timeout: Option<Duration>,
+
+    /// The idle timeout for chat response streams
+    #[cfg(feature = "tokio")]
+    stream_idle_timeout: Option<Duration>,
+
+    /// Whether chat response streams must end with the `[DONE]` sentinel
+    require_done: bool,
+
+    /// The number of times to resend a chat request with an empty reply
+    retry_empty_responses: u32,
+
+    /// The initial buffer capacity for decoding chat response streams
+    sse_buffer_capacity: Option<usize>,
+
+    /// The filter for message content
+    content_filter: Option<ContentFilter>,
+
+    /// Whether to also filter response content
+    filter_responses: bool,
+
+    /// The token that is cancelled when the client is shut down
+    shutdown: CancellationToken,
+
+    /// The cookie store, if the reqwest client was built with one
+    #[cfg(not(target_arch = "wasm32"))]
+    cookie_jar: Option<Arc<Jar>>,
 }
 
 impl Client {
+    /// Make a new client that serves the responses recorded in the given directory.
+    ///
+    /// No requests are sent over the network.
+    /// Responses for each url path are served in the order they were recorded,
+    /// which makes tests of code using this library fast and deterministic.
+    /// See [`ClientBuilder::record_to_dir`] for recording responses.
+    #[cfg(feature = "replay")]
+    pub fn replay_from_dir(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        Self::builder().replay_from_dir(dir).build()
+    }
+
     /// Make a new client.
     pub fn new() -> Self {
-        let client = reqwest::Client::builder()
-            .user_agent(USER_AGENT_STR)
-            .http1_title_case_headers()
-            .build()
-            .expect("failed to build client");
+        Self::builder().build().expect("failed to build client")
+    }
 
-        Self { client }
+    /// Make a new [`ClientBuilder`].
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
     }
 
-    /// Init a new chat.
+    /// Init a new chat with the default model.
+    ///
+    /// See [`ClientBuilder::default_model`].
     pub async fn init_chat(&self) -> Result<ChatRequest, Error> {
-        let url = "https://duckduckgo.com/duckchat/v1/status";
+        self.init_chat_with_model(self.default_model.clone()).await
+    }
+
+    /// Init a new chat with the given model.
+    ///
+    /// Models that are known to no longer work are rejected with [`Error::RetiredModel`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(model = tracing::field::Empty))
+    )]
+    pub async fn init_chat_with_model(
+        &self,
+        model: impl Into<Model>,
+    ) -> Result<ChatRequest, Error> {
+        let model = model.into();
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("model", tracing::field::display(&model));
+        model.check_not_retired()?;
+
+        let handshake = self.status_handshake().await?;
+
+        let mut request = ChatRequest::from_messages(Vec::new());
+        request.model = model;
+        request.set_vqd(handshake.vqd);
+        if let Some(vqd_hash) = handshake.vqd_hash {
+            request.set_vqd_hash(vqd_hash);
+        }
+
+        Ok(request)
+    }
+
+    /// Resume a chat from existing messages.
+    ///
+    /// This fetches a new vqd for the messages,
+    /// which is needed for chats that were saved and loaded.
+    /// The chat uses the default model of this client, like [`Client::init_chat`].
+    pub async fn resume_chat(&self, messages: Vec<ChatMessage>) -> Result<ChatRequest, Error> {
+        let model = self.default_model.clone();
+        model.check_not_retired()?;
+
+        let handshake = self.status_handshake().await?;
+
+        let mut request = ChatRequest::from_messages(messages);
+        request.model = model;
+        request.set_vqd(handshake.vqd);
+        if let Some(vqd_hash) = handshake.vqd_hash {
+            request.set_vqd_hash(vqd_hash);
+        }
+
+        Ok(request)
+    }
+
+    /// Get the cookies that are sent to the server, as a "Cookie" header value.
+    ///
+    /// This can be saved and restored with [`Client::set_cookies`] to keep a session across runs.
+    /// This is `None` if there are no cookies,
+    /// or if the client has no cookie store.
+    /// See [`ClientBuilder::cookie_store`].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn cookies(&self) -> Option<String> {
+        let jar = self.cookie_jar.as_ref()?;
+        let url = reqwest::Url::parse(&self.base_url).ok()?;
+        let cookies = jar.cookies(&url)?;
+
+        cookies.to_str().ok().map(|cookies| cookies.to_string())
+    }
+
+    /// Add cookies to the cookie store from a "Cookie" header value, like "a=1; b=2".
+    ///
+    /// This does nothing if the client has no cookie store.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_cookies(&self, cookies: &str) {
+        let jar = match self.cookie_jar.as_ref() {
+            Some(jar) => jar,
+            None => return,
+        };
+        let url = match reqwest::Url::parse(&self.base_url) {
+            Ok(url) => url,
+            Err(_error) => return,
+        };
+
+        for cookie in cookies.split(';').map(str::trim) {
+            if !cookie.is_empty() {
+                jar.add_cookie_str(cookie, &url);
+            }
+        }
+    }
+
+    /// Perform the status handshake and get the parsed response body.
+    pub async fn status(&self) -> Result<StatusInfo, Error> {
+        let handshake = self.status_handshake().await?;
+        Ok(StatusInfo::from_body(&handshake.body))
+    }
+
+    /// Get the models that are currently supported.
+    ///
+    /// This performs the status handshake and reads the models it lists.
+    /// If it does not list any, the models known to this library are returned.
+    pub async fn list_models(&self) -> Result<Vec<String>, Error> {
+        let status = self.status().await?;
+        if !status.models.is_empty() {
+            return Ok(status.models);
+        }
+
+        Ok(Model::KNOWN
+            .iter()
+            .map(|model| model.as_str().to_string())
+            .collect())
+    }
+
+    /// Check that DuckDuckGo is reachable and handing out vqd tokens.
+    ///
+    /// This only performs the status handshake, without starting a chat,
+    /// so it is cheap enough for readiness probes.
+    /// Failures can be classified with [`Error::is_blocked`] and [`Error::is_unavailable`].
+    pub async fn health_check(&self) -> Result<(), Error> {
+        self.status_handshake().await?;
+        Ok(())
+    }
+
+    /// Perform the status handshake to get a new vqd token.
+    ///
+    /// This can be used to pre-fetch tokens,
+    /// or to attach a token to a deserialized [`ChatRequest`] with [`ChatRequest::set_vqd`].
+    /// Any "x-vqd-hash-1" challenge from the handshake is discarded;
+    /// use [`Client::resume_chat`] to keep it.
+    pub async fn fetch_vqd(&self) -> Result<String, Error> {
+        let handshake = self.status_handshake().await?;
+        Ok(handshake.vqd)
+    }
+
+    /// Perform the status handshake, reporting any error to the observer.
+    async fn status_handshake(&self) -> Result<StatusHandshake, Error> {
+        let result = self.try_status_handshake().await;
+        self.observe_error(result)
+    }
+
+    /// Perform the status handshake.
+    async fn try_status_handshake(&self) -> Result<StatusHandshake, Error> {
+        let url = format!("{}{STATUS_PATH}", self.base_url);
         let response = self
-            .client
-            .get(url)
-            .header("x-vqd-accept", "1")
-            .send()
-            .await?
-            .error_for_status()?;
+            .send_with_retry(
+                || {
+                    let mut request = self
+                        .client
+                        .get(&url)
+                        .headers(self.default_headers.clone())
+                        .header("x-vqd-accept", "1");
+                    if let Some(timeout) = self.handshake_timeout {
+                        request = request.timeout(timeout);
+                    }
+                    request
+                },
+                |request| self.transport.status(request),
+            )
+            .await?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(status = %response.status(), "received status response");
+        let response = check_status(response).await?;
         let vqd = response
             .headers()
             .get("x-vqd-4")
             .and_then(|header| header.to_str().ok())
             .ok_or(Error::MissingVqd)?
             .to_string();
-        let _text = response.text().await?;
+        if !is_plausible_vqd(&vqd) {
+            return Err(Error::InvalidVqd(vqd));
+        }
+        let vqd_hash = get_vqd_hash(response.headers());
+        let body = response.text().await?;
 
-        Ok(ChatRequest {
-            messages: Vec::new(),
-            model: DEFAULT_MODEL.into(),
-            vqd: Some(vqd),
+        Ok(StatusHandshake {
+            vqd,
+            vqd_hash,
+            body,
         })
     }
 
     /// Chat with an AI.
+    ///
+    /// The server rotates the vqd token on each request.
+    /// The new token is available from [`ChatResponseStream::vqd`],
+    /// and should be stored in the request for later turns.
+    ///
+    /// If the server rejects the vqd token,
+    /// a new one is fetched and the request is retried once.
+    ///
+    /// The conversation is checked with [`ChatRequest::validate`] before sending.
     pub async fn chat(&self, request: &ChatRequest) -> Result<ChatResponseStream, Error> {
-        let vqd = request.vqd.as_deref().ok_or(Error::MissingVqd)?;
+        self.chat_with_headers(request, &HeaderMap::new()).await
+    }
+
+    /// Chat with an AI, sending extra headers with the request.
+    ///
+    /// These are merged with the client's default headers, replacing any with the same name.
+    /// The "x-vqd-4" header is always set from the request, and cannot be replaced.
+    ///
+    /// See [`Client::chat`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(model = %request.model, message_count = request.messages.len())
+        )
+    )]
+    pub async fn chat_with_headers(
+        &self,
+        request: &ChatRequest,
+        extra_headers: &HeaderMap,
+    ) -> Result<ChatResponseStream, Error> {
+        let result = self.try_chat_with_headers(request, extra_headers).await;
+        self.observe_error(result)
+    }
+
+    /// Send a chat request and start the response stream.
+    async fn try_chat_with_headers(
+        &self,
+        request: &ChatRequest,
+        extra_headers: &HeaderMap,
+    ) -> Result<ChatResponseStream, Error> {
+        request.validate()?;
+
+        let filtered_request;
+        let request = match self.content_filter.as_ref() {
+            Some(filter) => {
+                filtered_request = filter.filter_request(request)?;
+                &filtered_request
+            }
+            None => request,
+        };
+
+        let vqd = request.vqd().ok_or(Error::MissingVqd)?;
+        let vqd_hash = request.vqd_hash();
         let response = self
-            .client
-            .post(CHAT_URL)
+            .send_chat(request, vqd, vqd_hash, extra_headers)
+            .await?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(status = %response.status(), "received chat response");
+
+        let (response, new_handshake) = match check_status(response).await {
+            Ok(response) => (response, None),
+            Err(error) => {
+                if !is_invalid_vqd_error(&error) {
+                    return Err(error);
+                }
+                #[cfg(feature = "tracing")]
+                tracing::debug!("vqd was rejected, retrying with a new one");
+
+                // Only retry once, surfacing the original error if that fails.
+                let handshake = match self.try_status_handshake().await {
+                    Ok(handshake) => handshake,
+                    Err(_retry_error) => return Err(error),
+                };
+                if let Some(observer) = self.observer.as_ref() {
+                    observer.on_vqd_refresh();
+                }
+                let response = match self
+                    .send_chat(
+                        request,
+                        &handshake.vqd,
+                        handshake.vqd_hash.as_deref(),
+                        extra_headers,
+                    )
+                    .await
+                {
+                    Ok(response) if response.status().is_success() => response,
+                    _ => return Err(error),
+                };
+
+                (response, Some(handshake))
+            }
+        };
+        let (new_vqd, new_vqd_hash) = match new_handshake {
+            Some(handshake) => (Some(handshake.vqd), handshake.vqd_hash),
+            None => (None, None),
+        };
+        let new_vqd = response
+            .headers()
+            .get("x-vqd-4")
+            .and_then(|header| header.to_str().ok())
+            .map(|vqd| vqd.to_string())
+            .or(new_vqd);
+        let new_vqd_hash = get_vqd_hash(response.headers()).or(new_vqd_hash);
+        let response = check_event_stream(response).await?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            vqd_rotated = new_vqd.is_some(),
+            "starting chat response stream"
+        );
+
+        #[cfg(not(target_arch = "wasm32"))]
+        let reader = SseStream::new(response.bytes_stream(), self.sse_buffer_capacity);
+
+        // Browser streams are not Send, so the whole body is read before decoding.
+        #[cfg(target_arch = "wasm32")]
+        let reader = {
+            let body = response.bytes().await?;
+            SseStream::new(
+                tokio_stream::once(Ok::<_, std::convert::Infallible>(body)),
+                None,
+            )
+        };
+
+        let mut stream = ChatResponseStream::new(
+            Box::pin(reader),
+            request.model.clone(),
+            new_vqd,
+            new_vqd_hash,
+        );
+        if let Some(filter) = self
+            .content_filter
+            .clone()
+            .filter(|_| self.filter_responses)
+        {
+            stream = stream.with_content_filter(filter);
+        }
+        if self.require_done {
+            stream = stream.with_require_done();
+        }
+        if let Some(observer) = self.observer.clone() {
+            stream = stream.with_observer(observer);
+        }
+        stream = stream.with_cancellation(self.shutdown.clone());
+        #[cfg(feature = "tokio")]
+        if let Some(timeout) = self.stream_idle_timeout {
+            stream = stream.with_idle_timeout(timeout);
+        }
+
+        Ok(stream)
+    }
+
+    /// Build the chat request that [`Client::chat`] would send, without sending it.
+    ///
+    /// The url, headers, and body of the returned request can be inspected for debugging.
+    /// The conversation is validated and filtered, the vqd hash solver is run,
+    /// and the request middleware is applied, just like when sending.
+    pub fn dry_run_chat(&self, request: &ChatRequest) -> Result<reqwest::Request, Error> {
+        request.validate()?;
+        let request = match self.content_filter.as_ref() {
+            Some(filter) => Cow::Owned(filter.filter_request(request)?),
+            None => Cow::Borrowed(request),
+        };
+
+        let vqd = request.vqd().ok_or(Error::MissingVqd)?;
+        let headers = self.chat_headers(request.vqd_hash(), &HeaderMap::new());
+        let builder = self.build_chat_request(&request, vqd, &headers);
+        Ok(self.apply_middleware(builder).build()?)
+    }
+
+    /// Chat with an AI, getting a boxed stream of content parts.
+    ///
+    /// This is [`ChatResponseStream::text_stream`] as a concrete type,
+    /// which is easier to return from functions or pass to web frameworks.
+    pub async fn chat_stream_text(
+        &self,
+        request: &ChatRequest,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<String, Error>> + Send>>, Error> {
+        let stream = self.chat(request).await?;
+        Ok(Box::pin(stream.text_stream()))
+    }
+
+    /// Shut down this client and all of its clones.
+    ///
+    /// Every response stream from these clients ends as if it was cancelled,
+    /// which drops its http connection and lets tasks reading it,
+    /// like [`ChatResponseStream::into_channel`], finish.
+    /// Idle pooled connections are closed once the last clone of the client is dropped.
+    /// Afterwards, no more requests are sent,
+    /// and methods that would send one fail with [`Error::ShutDown`].
+    ///
+    /// For a graceful shutdown, stop starting new chats, call this,
+    /// then drop the client and any remaining streams.
+    pub fn shutdown(self) {
+        self.shutdown.cancel();
+    }
+
+    /// Check whether this client was shut down.
+    ///
+    /// See [`Client::shutdown`].
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// Chat with an AI, ending the response stream once the token is cancelled.
+    ///
+    /// See [`ChatResponseStream::with_cancellation`].
+    pub async fn chat_with_cancel(
+        &self,
+        request: &ChatRequest,
+        token: CancellationToken,
+    ) -> Result<ChatResponseStream, Error> {
+        let stream = self.chat(request).await?;
+        Ok(stream.with_cancellation(token))
+    }
+
+    /// Chat with an AI, and wait for the complete response.
+    ///
+    /// The response is not added to the request.
+    /// See [`ClientBuilder::resume_on_disconnect`] for resuming cut off responses.
+    pub async fn chat_once(&self, request: &ChatRequest) -> Result<ChatMessage, Error> {
+        let mut stream = self.chat(request).await?;
+        self.collect_reply(request, &mut stream).await
+    }
+
+    /// Chat with an AI in a single call, without managing a [`ChatRequest`].
+    ///
+    /// This fetches a new vqd, sends the messages, and waits for the complete reply.
+    pub async fn chat_with_messages(
+        &self,
+        model: impl Into<Model>,
+        messages: &[ChatMessage],
+    ) -> Result<ChatMessage, Error> {
+        let mut request = self.init_chat_with_model(model).await?;
+        request.messages.extend_from_slice(messages);
+        self.chat_once(&request).await
+    }
+
+    /// Send the same messages to several models at once.
+    ///
+    /// Each model gets its own vqd, since a vqd is rotated on every request and cannot be shared.
+    /// The requests run concurrently,
+    /// and the results are returned in the same order as the models.
+    /// A failure for one model does not affect the others.
+    pub async fn chat_all(
+        &self,
+        model_list: &[String],
+        messages: &[ChatMessage],
+    ) -> Vec<Result<ChatMessage, Error>> {
+        join_all(
+            model_list
+                .iter()
+                .map(|model| self.chat_with_messages(model.as_str(), messages)),
+        )
+        .await
+    }
+
+    /// Chat with an AI, calling the given function with each content part as it arrives.
+    ///
+    /// The complete response is returned, but not added to the request.
+    pub async fn chat_with_callback<F>(
+        &self,
+        request: &ChatRequest,
+        on_content: F,
+    ) -> Result<ChatMessage, Error>
+    where
+        F: FnMut(&str),
+    {
+        let mut stream = self.chat(request).await?;
+        stream.collect_with_callback(on_content).await
+    }
+
+    /// Chat with an AI, and add the complete response to the request.
+    ///
+    /// The vqd token of the request is also rotated.
+    /// This allows for multi-turn chats by pushing a user message
+    /// and calling this method for each turn.
+    /// See [`ClientBuilder::resume_on_disconnect`] for resuming cut off responses.
+    pub async fn chat_and_append(&self, request: &mut ChatRequest) -> Result<ChatMessage, Error> {
+        let mut stream = self.chat(request).await?;
+        let result = self.collect_reply(request, &mut stream).await;
+        if let Some(vqd) = stream.vqd() {
+            request.set_vqd(vqd.into());
+        }
+        if let Some(vqd_hash) = stream.vqd_hash() {
+            request.set_vqd_hash(vqd_hash.into());
+        }
+
+        let message = result?;
+        request.messages.push(message.clone());
+
+        Ok(message)
+    }
+
+    /// Regenerate the last assistant reply.
+    ///
+    /// The last reply is removed and the user message before it is sent again,
+    /// then the new reply is added to the request.
+    /// The vqd token is rotated as in [`Client::chat_and_append`].
+    /// If this fails, the old reply is put back.
+    /// See [`ChatRequest::regenerate_last`].
+    pub async fn regenerate(&self, request: &mut ChatRequest) -> Result<ChatMessage, Error> {
+        let old_reply = request.regenerate_last()?;
+        match self.chat_and_append(request).await {
+            Ok(message) => Ok(message),
+            Err(error) => {
+                request.messages.push(old_reply);
+                Err(error)
+            }
+        }
+    }
+
+    /// Collect a response stream into a message, resending the request if the reply is empty.
+    ///
+    /// Each retry uses the latest vqd.
+    /// The stream is replaced with the stream of the last attempt.
+    /// See [`ClientBuilder::retry_empty_responses`].
+    async fn collect_reply(
+        &self,
+        request: &ChatRequest,
+        stream: &mut ChatResponseStream,
+    ) -> Result<ChatMessage, Error> {
+        let mut retries = 0;
+        loop {
+            let result = self.collect_resuming(request, stream).await;
+            let is_empty = match &result {
+                Ok(message) => message.content.is_empty(),
+                Err(error) => matches!(error, Error::StreamEmpty),
+            };
+            if !is_empty || retries >= self.retry_empty_responses {
+                return result;
+            }
+            retries += 1;
+
+            #[cfg(feature = "tracing")]
+            tracing::debug!(retries, "response was empty, retrying");
+
+            let mut retry_request = request.clone();
+            if let Some(vqd) = stream.vqd() {
+                retry_request.set_vqd(vqd.into());
+            }
+            if let Some(vqd_hash) = stream.vqd_hash() {
+                retry_request.set_vqd_hash(vqd_hash.into());
+            }
+            *stream = self.chat(&retry_request).await?;
+        }
+    }
+
+    /// Collect a response stream into a message.
+    ///
+    /// If resuming is enabled and the connection drops mid-response,
+    /// the partial response is sent back as context and the continuation is stitched onto it.
+    /// The stream is replaced with the stream of the last continuation.
+    async fn collect_resuming(
+        &self,
+        request: &ChatRequest,
+        stream: &mut ChatResponseStream,
+    ) -> Result<ChatMessage, Error> {
+        let mut result = stream.collect_into_chat_message().await;
+        if !self.resume_on_disconnect {
+            return result;
+        }
+
+        let mut content = String::new();
+        let mut resumes = 0;
+        loop {
+            let (partial, source) = match result {
+                Ok(message) => {
+                    content.push_str(strip_overlap(&content, &message.content));
+                    return Ok(ChatMessage {
+                        role: message.role,
+                        content,
+                        attachments: Vec::new(),
+                    });
+                }
+                Err(Error::PartialResponse {
+                    content: partial,
+                    source,
+                }) => (partial, *source),
+                // The connection may have dropped before any content arrived.
+                Err(error) => (String::new(), error),
+            };
+            content.push_str(strip_overlap(&content, &partial));
+            if resumes >= MAX_RESUMES || !is_disconnect_error(&source) {
+                return Err(source.with_partial_content(content));
+            }
+            resumes += 1;
+
+            #[cfg(feature = "tracing")]
+            tracing::debug!(resumes, "response was cut off, resuming");
+
+            let mut resume_request = request.clone();
+            if let Some(vqd) = stream.vqd() {
+                resume_request.set_vqd(vqd.into());
+            }
+            if let Some(vqd_hash) = stream.vqd_hash() {
+                resume_request.set_vqd_hash(vqd_hash.into());
+            }
+            // Without any content, there is nothing to continue, so the request is just resent.
+            if !content.is_empty() {
+                resume_request
+                    .messages
+                    .push(ChatMessage::assistant(content.clone()));
+                resume_request
+                    .messages
+                    .push(ChatMessage::user(RESUME_PROMPT));
+            }
+
+            *stream = match self.chat(&resume_request).await {
+                Ok(stream) => stream,
+                Err(error) => return Err(error.with_partial_content(content)),
+            };
+            result = stream.collect_into_chat_message().await;
+        }
+    }
+
+    /// Send a chat request with the given vqd.
+    async fn send_chat(
+        &self,
+        request: &ChatRequest,
+        vqd: &str,
+        vqd_hash: Option<&str>,
+        extra_headers: &HeaderMap,
+    ) -> Result<reqwest::Response, Error> {
+        let headers = self.chat_headers(vqd_hash, extra_headers);
+        self.send_with_retry(
+            || self.build_chat_request(request, vqd, &headers),
+            |request| self.transport.chat(request),
+        )
+        .await
+    }
+
+    /// Get the headers for a chat request, solving the "x-vqd-hash-1" challenge if possible.
+    fn chat_headers(&self, vqd_hash: Option<&str>, extra_headers: &HeaderMap) -> HeaderMap {
+        let mut headers = self.default_headers.clone();
+        headers.extend(extra_headers.clone());
+        headers.remove("x-vqd-4");
+
+        let solved_vqd_hash = self
+            .vqd_hash_solver
+            .as_ref()
+            .zip(vqd_hash)
+            .and_then(|(solver, challenge)| (solver.0)(challenge))
+            .and_then(|solution| HeaderValue::try_from(solution).ok());
+        if let Some(solved_vqd_hash) = solved_vqd_hash {
+            headers.insert(VQD_HASH_HEADER, solved_vqd_hash);
+        }
+
+        headers
+    }
+
+    /// Build a chat request with the given vqd and headers.
+    fn build_chat_request(
+        &self,
+        request: &ChatRequest,
+        vqd: &str,
+        headers: &HeaderMap,
+    ) -> reqwest::RequestBuilder {
+        self.client
+            .post(format!("{}{CHAT_PATH}", self.base_url))
+            .headers(headers.clone())
             .header("x-vqd-4", vqd)
-            .json(request)
-            .send()
-            .await?
-            .error_for_status()?;
-        let stream = response.bytes_stream().map_err(std::io::Error::other);
-        let stream_reader = StreamReader::new(stream);
-        let codec = SseCodec::new();
-        let reader = FramedRead::new(stream_reader, codec);
+            .json(&request.wire())
+    }
+
+    /// Apply the request middleware, if any.
+    fn apply_middleware(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self.request_middleware.as_ref() {
+            Some(middleware) => (middleware.0)(request),
+            None => request,
+        }
+    }
+
+    /// Report an error to the observer, if any.
+    fn observe_error<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        if let (Some(observer), Err(error)) = (self.observer.as_ref(), result.as_ref()) {
+            observer.on_error(error);
+        }
+        result
+    }
+
+    /// Send a request, retrying with exponential backoff if it is rate limited.
+    async fn send_with_retry<'a>(
+        &'a self,
+        make_request: impl Fn() -> reqwest::RequestBuilder,
+        send: impl Fn(reqwest::Request) -> TransportFuture<'a>,
+    ) -> Result<reqwest::Response, Error> {
+        let mut attempt = 0;
+        loop {
+            if self.shutdown.is_cancelled() {
+                return Err(Error::ShutDown);
+            }
+
+            let request = self.apply_middleware(make_request()).build()?;
+            if let Some(observer) = self.observer.as_ref() {
+                observer.on_request(&request);
+            }
+            let response = send(request).await?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response);
+            }
+
+            let retry_after = parse_retry_after(response.headers());
+            let reset_at = parse_reset_at(response.headers(), retry_after);
+            // Building the client fails if retries are enabled without a timer.
+            let sleep = match self.sleep.as_ref() {
+                Some(sleep) if attempt < self.max_retries => sleep,
+                _ => {
+                    return Err(Error::RateLimited {
+                        retry_after,
+                        reset_at,
+                        headers: Box::new(response.headers().clone()),
+                    });
+                }
+            };
+
+            let backoff = INITIAL_BACKOFF.saturating_mul(2_u32.saturating_pow(attempt));
+            let wait = retry_after
+                .or_else(|| reset_at?.duration_since(SystemTime::now()).ok())
+                .unwrap_or(backoff);
+            (sleep.0)(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Check whether a vqd looks like a real token.
+///
+/// Tokens are opaque, so this only rejects blank values and values with whitespace or control characters.
+fn is_plausible_vqd(vqd: &str) -> bool {
+    !vqd.is_empty() && vqd.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// Parse the "Retry-After" header, which is either a number of seconds or an http date.
+///
+/// Dates in the past are treated as no wait.
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    // There is no clock on wasm.
+    if cfg!(target_arch = "wasm32") {
+        return None;
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
 
-        Ok(ChatResponseStream::new(Box::pin(reader)))
+/// Estimate when a rate limit resets from the headers of a rate limited response.
+///
+/// Reset headers may be either a number of seconds or a unix timestamp,
+/// so large values are assumed to be timestamps.
+fn parse_reset_at(headers: &HeaderMap, retry_after: Option<Duration>) -> Option<SystemTime> {
+    // There is no clock on wasm.
+    if cfg!(target_arch = "wasm32") {
+        return None;
     }
+
+    if let Some(retry_after) = retry_after {
+        return SystemTime::now().checked_add(retry_after);
+    }
+
+    let reset: u64 = ["ratelimit-reset", "x-ratelimit-reset"]
+        .iter()
+        .find_map(|name| headers.get(*name))?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    if reset >= MIN_RESET_TIMESTAMP {
+        UNIX_EPOCH.checked_add(Duration::from_secs(reset))
+    } else {
+        SystemTime::now().checked_add(Duration::from_secs(reset))
+    }
+}
+
+/// The result of a status handshake.
+struct StatusHandshake {
+    /// The vqd token
+    vqd: String,
+
+    /// The "x-vqd-hash-1" challenge, if one was sent
+    vqd_hash: Option<String>,
+
+    /// The response body
+    body: String,
+}
+
+/// Get the "x-vqd-hash-1" challenge from response headers.
+fn get_vqd_hash(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(VQD_HASH_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .map(|vqd_hash| vqd_hash.to_string())
+}
+
+/// A function that computes the "x-vqd-hash-1" header from the server's challenge.
+#[derive(Clone)]
+struct VqdHashSolver(Arc<dyn Fn(&str) -> Option<String> + Send + Sync>);
+
+impl std::fmt::Debug for VqdHashSolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("VqdHashSolver").finish()
+    }
+}
+
+/// The future returned by a [`ClientBuilder::sleep_fn`] function.
+#[cfg(not(target_arch = "wasm32"))]
+pub type SleepFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The future returned by a [`ClientBuilder::sleep_fn`] function.
+#[cfg(target_arch = "wasm32")]
+pub type SleepFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+/// A function that waits for the given duration.
+#[derive(Clone)]
+struct SleepFn(Arc<dyn Fn(Duration) -> SleepFuture + Send + Sync>);
+
+impl SleepFn {
+    /// Get the default timer, tokio's.
+    #[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+    fn default_timer() -> Option<Self> {
+        Some(Self(Arc::new(|duration| -> SleepFuture {
+            Box::pin(tokio::time::sleep(duration))
+        })))
+    }
+
+    /// Get the default timer, which does not exist without tokio.
+    #[cfg(not(all(feature = "tokio", not(target_arch = "wasm32"))))]
+    fn default_timer() -> Option<Self> {
+        None
+    }
+}
+
+impl std::fmt::Debug for SleepFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SleepFn").finish()
+    }
+}
+
+/// A function that customizes a request before it is sent.
+#[derive(Clone)]
+struct RequestMiddleware(
+    Arc<dyn Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder + Send + Sync>,
+);
+
+impl std::fmt::Debug for RequestMiddleware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("RequestMiddleware").finish()
+    }
+}
+
+/// Check that a response has a success status.
+///
+/// If it does not, the body is read into an [`Error::Http`].
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await?;
+    Err(Error::Http { status, body })
+}
+
+/// Check that a response is an event stream, returning an error with the start of the body if not.
+///
+/// Responses without a content type are assumed to be event streams.
+async fn check_event_stream(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let content_type = match response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|header| header.to_str().ok())
+    {
+        Some(content_type) => content_type,
+        None => return Ok(response),
+    };
+    let mime_type = content_type.split(';').next().unwrap_or_default().trim();
+    if mime_type.eq_ignore_ascii_case("text/event-stream") {
+        return Ok(response);
+    }
+
+    let content_type = content_type.to_string();
+    let body = response.text().await?;
+    let body_snippet = body.chars().take(BODY_SNIPPET_LEN).collect();
+    Err(Error::UnexpectedContentType {
+        content_type,
+        body_snippet,
+    })
+}
+
+/// Check if an error was caused by an invalid or expired vqd.
+fn is_invalid_vqd_error(error: &Error) -> bool {
+    match error {
+        Error::Http { status, body } => {
+            status.is_client_error() && body.contains("ERR_INVALID_VQD")
+        }
+        _ => false,
+    }
+}
+
+/// Check if an error was caused by a dropped connection.
+///
+/// Only connection failures and bodies that were cut off count,
+/// not other io or http errors, which would likely fail again.
+fn is_disconnect_error(error: &Error) -> bool {
+    if matches!(error, Error::IncompleteStream) {
+        return true;
+    }
+
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<std::io::Error>() {
+            if matches!(
+                error.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+            ) {
+                return true;
+            }
+
+            // The source of an io error skips the error it wraps, so look at that first.
+            if let Some(inner) = error.get_ref() {
+                source = Some(inner);
+                continue;
+            }
+        }
+        if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+            if error.is_body() || error.is_connect() {
+                return true;
+            }
+        }
+        source = error.source();
+    }
+
+    false
+}
+
+/// Get the part of a continuation that does not repeat the end of the existing content.
+///
+/// Short overlaps are kept, as they are likely to be a coincidence.
+pub(crate) fn strip_overlap<'a>(existing: &str, continuation: &'a str) -> &'a str {
+    let max = existing
+        .len()
+        .min(continuation.len())
+        .min(MAX_RESUME_OVERLAP);
+    let overlap = (MIN_RESUME_OVERLAP..=max)
+        .rev()
+        .find(|&len| continuation.is_char_boundary(len) && existing.ends_with(&continuation[..len]))
+        .unwrap_or(0);
+
+    &continuation[overlap..]
 }
 
 impl Default for Client {
@@ -79,3 +1096,494 @@ impl Default for Client {
         Self::new()
     }
 }
+
+/// A builder for a [`Client`].
+#[derive(Debug, Default)]
+pub struct ClientBuilder {
+    user_agent: Option<String>,
+    timeout: Option<Duration>,
+    reqwest_client: Option<reqwest::Client>,
+    transport: Option<Arc<dyn Transport>>,
+    max_retries: u32,
+    pool_max_idle_per_host: Option<usize>,
+    #[cfg(not(target_arch = "wasm32"))]
+    proxies: Vec<reqwest::Proxy>,
+    #[cfg(not(target_arch = "wasm32"))]
+    no_proxy: bool,
+    base_url: Option<String>,
+    default_model: Option<Model>,
+    default_headers: HeaderMap,
+    vqd_hash_solver: Option<VqdHashSolver>,
+    request_middleware: Option<RequestMiddleware>,
+    sleep: Option<SleepFn>,
+    observer: Option<Arc<dyn Observer>>,
+    resume_on_disconnect: bool,
+    connect_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    #[cfg(feature = "tokio")]
+    stream_idle_timeout: Option<Duration>,
+    require_done: bool,
+    retry_empty_responses: u32,
+    sse_buffer_capacity: Option<usize>,
+    content_filter: Option<ContentFilter>,
+    filter_responses: bool,
+    #[cfg(not(target_arch = "wasm32"))]
+    cookie_store: Option<bool>,
+    #[cfg(not(target_arch = "wasm32"))]
+    cookie_jar: Option<Arc<Jar>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    http1_title_case_headers: Option<bool>,
+    #[cfg(not(target_arch = "wasm32"))]
+    http2_prior_knowledge: bool,
+    #[cfg(feature = "replay")]
+    record_dir: Option<PathBuf>,
+    #[cfg(feature = "replay")]
+    replay_dir: Option<PathBuf>,
+}
+
+impl ClientBuilder {
+    /// Make a new [`ClientBuilder`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the user agent.
+    ///
+    /// By default, a browser user agent is used.
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Set a timeout for each request.
+    ///
+    /// This applies from when a request starts until the response body is done,
+    /// so it also limits how long a chat response may stream.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set a timeout for connecting to the server.
+    ///
+    /// This only limits how long establishing a connection may take.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set a timeout for the status handshake.
+    ///
+    /// This applies to [`Client::init_chat`], [`Client::fetch_vqd`],
+    /// and the other methods that perform the handshake,
+    /// but not to chat requests, which may legitimately stream for minutes.
+    /// See [`ClientBuilder::stream_idle_timeout`] for chat requests.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Set an idle timeout for every chat response stream.
+    ///
+    /// See [`ChatResponseStream::with_idle_timeout`].
+    #[cfg(feature = "tokio")]
+    pub fn stream_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.stream_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail every chat response stream that ends without the `[DONE]` sentinel.
+    ///
+    /// This is off by default.
+    /// See [`ChatResponseStream::with_require_done`].
+    pub fn require_done(mut self, require_done: bool) -> Self {
+        self.require_done = require_done;
+        self
+    }
+
+    /// Set the number of times to resend a chat request if the reply is empty.
+    ///
+    /// The server sometimes ends a response without any content.
+    /// With this, [`Client::chat_once`], [`Client::chat_and_append`],
+    /// and the methods built on them send the request again, with the latest vqd.
+    /// By default, empty replies are not retried.
+    pub fn retry_empty_responses(mut self, retries: u32) -> Self {
+        self.retry_empty_responses = retries;
+        self
+    }
+
+    /// Set the initial buffer capacity, in bytes, for decoding chat response streams.
+    ///
+    /// The buffer still grows as needed for large events.
+    /// By default, the capacity is 8 KiB.
+    /// This is ignored on wasm, where the whole response body is buffered.
+    pub fn sse_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.sse_buffer_capacity = Some(capacity);
+        self
+    }
+
+    /// Set the number of times to retry a rate-limited request.
+    ///
+    /// Retries use exponential backoff,
+    /// unless the server says how long to wait.
+    /// By default, rate-limited requests are not retried.
+    ///
+    /// Waiting needs a timer, which is tokio's unless one is set with [`ClientBuilder::sleep_fn`].
+    /// Without the `tokio` feature, or on wasm, building a client that retries without one is an error.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Set the function used to wait before retrying a rate-limited request.
+    ///
+    /// This allows retries on any executor,
+    /// like `.sleep_fn(|duration| Box::pin(async_std::task::sleep(duration)))` with async-std.
+    /// By default, tokio's timer is used with the `tokio` feature.
+    /// See [`ClientBuilder::max_retries`].
+    pub fn sleep_fn<F>(mut self, sleep: F) -> Self
+    where
+        F: Fn(Duration) -> SleepFuture + Send + Sync + 'static,
+    {
+        self.sleep = Some(SleepFn(Arc::new(sleep)));
+        self
+    }
+
+    /// Set the maximum number of idle connections kept per host.
+    ///
+    /// By default, this is unlimited.
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// Add a proxy for all requests.
+    ///
+    /// This may be called multiple times to add multiple proxies.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.proxies.push(proxy);
+        self
+    }
+
+    /// Disable system proxies.
+    ///
+    /// Proxies added with [`ClientBuilder::proxy`] are still used.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn no_proxy(mut self) -> Self {
+        self.no_proxy = true;
+        self
+    }
+
+    /// Set whether to keep cookies from responses and send them with later requests.
+    ///
+    /// The server may set cookies during the status handshake that matter for chat requests.
+    /// By default, this is enabled.
+    ///
+    /// A preconfigured reqwest client cannot have a cookie store added,
+    /// so enabling this together with [`ClientBuilder::reqwest_client`] is an error.
+    /// Use [`ClientBuilder::cookie_provider`] to share the client's jar instead.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn cookie_store(mut self, cookie_store: bool) -> Self {
+        self.cookie_store = Some(cookie_store);
+        self
+    }
+
+    /// Set the cookie jar to keep cookies in.
+    ///
+    /// This enables the cookie store.
+    /// For a preconfigured reqwest client, this should be the jar it was built with,
+    /// so that [`Client::cookies`] and [`Client::set_cookies`] work.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn cookie_provider(mut self, jar: Arc<Jar>) -> Self {
+        self.cookie_jar = Some(jar);
+        self
+    }
+
+    /// Set whether to send http/1 header names in title case, like a browser.
+    ///
+    /// This has no effect on http/2 connections.
+    /// By default, this is enabled.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn http1_title_case_headers(mut self, title_case: bool) -> Self {
+        self.http1_title_case_headers = Some(title_case);
+        self
+    }
+
+    /// Only use http/2, without negotiating it first.
+    ///
+    /// The server must support http/2, or requests will fail.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn http2_prior_knowledge(mut self) -> Self {
+        self.http2_prior_knowledge = true;
+        self
+    }
+
+    /// Set the model that [`Client::init_chat`] uses.
+    ///
+    /// By default, this is [`Model::Gpt4OMini`].
+    pub fn default_model(mut self, model: impl Into<Model>) -> Self {
+        self.default_model = Some(model.into());
+        self
+    }
+
+    /// Set the base url that requests are sent to.
+    ///
+    /// This is useful for testing or for going through a reverse proxy.
+    /// By default, this is "https://duckduckgo.com".
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Add a header that is sent with every request.
+    ///
+    /// This also applies to a preconfigured reqwest client.
+    pub fn default_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.default_headers.insert(name, value);
+        self
+    }
+
+    /// Set the solver for the "x-vqd-hash-1" anti-bot challenge.
+    ///
+    /// The status handshake and each chat response may include a challenge in this header.
+    /// The challenge is a base64-encoded script that must be run in a browser-like environment,
+    /// so this library does not compute the header itself.
+    /// Without a solver, the server may reject chat requests.
+    /// The solver is given the latest challenge for a chat before each chat request,
+    /// and its result is sent in the "x-vqd-hash-1" header.
+    /// If there is no solver, or it returns `None`, the header is not sent.
+    pub fn vqd_hash_solver<F>(mut self, solver: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.vqd_hash_solver = Some(VqdHashSolver(Arc::new(solver)));
+        self
+    }
+
+    /// Set a function that customizes each request just before it is sent.
+    ///
+    /// This applies to the status handshake and to chat requests,
+    /// and is an escape hatch for headers or query parameters that this library does not set.
+    /// The library's own headers are applied first, so they may be overridden.
+    pub fn request_middleware<F>(mut self, middleware: F) -> Self
+    where
+        F: Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder + Send + Sync + 'static,
+    {
+        self.request_middleware = Some(RequestMiddleware(Arc::new(middleware)));
+        self
+    }
+
+    /// Set an observer that is told about requests, tokens, and errors.
+    ///
+    /// This is useful for collecting metrics.
+    /// See [`Observer`].
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Set a filter for message content, like a moderation check.
+    ///
+    /// Before a chat request is sent, the filter is run on each user message.
+    /// If it rejects any, the request fails with [`Error::Filtered`].
+    /// Redacted messages are only changed in the sent request, not in the caller's request.
+    /// See [`ClientBuilder::filter_responses`] to also filter response content.
+    pub fn content_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&str) -> FilterAction + Send + Sync + 'static,
+    {
+        self.content_filter = Some(ContentFilter(Arc::new(filter)));
+        self
+    }
+
+    /// Also run the content filter on each response content part.
+    ///
+    /// If a part is rejected, the stream yields [`Error::Filtered`] and then ends.
+    /// By default, responses are not filtered.
+    pub fn filter_responses(mut self, filter_responses: bool) -> Self {
+        self.filter_responses = filter_responses;
+        self
+    }
+
+    /// Resume responses that are cut off by a dropped connection.
+    ///
+    /// When enabled, [`Client::chat_once`] and [`Client::chat_and_append`]
+    /// send the partial response back as context and ask the model to continue,
+    /// stitching the continuation onto the partial response.
+    /// Text that the continuation repeats from the end of the partial response is removed.
+    /// A response is resumed at most 3 times.
+    /// By default, this is disabled.
+    pub fn resume_on_disconnect(mut self, resume_on_disconnect: bool) -> Self {
+        self.resume_on_disconnect = resume_on_disconnect;
+        self
+    }
+
+    /// Use a preconfigured reqwest client.
+    ///
+    /// This client is used as-is,
+    /// and other http options from this builder are ignored.
+    /// See [`ClientBuilder::cookie_provider`] for using its cookies.
+    pub fn reqwest_client(mut self, client: reqwest::Client) -> Self {
+        self.reqwest_client = Some(client);
+        self
+    }
+
+    /// Set the transport that requests are sent with.
+    ///
+    /// Requests are still built with the reqwest client,
+    /// but are sent through the given transport instead.
+    /// By default, a [`ReqwestTransport`](crate::transport::ReqwestTransport) is used.
+    pub fn transport<T>(mut self, transport: T) -> Self
+    where
+        T: Transport + 'static,
+    {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Record every response to the given directory.
+    ///
+    /// Each response is saved as a JSON file named after its url path and order,
+    /// like "status-0.json" or "chat-1.json".
+    /// Response bodies are read completely before they are returned,
+    /// so responses are not streamed while recording.
+    /// See [`Client::replay_from_dir`].
+    #[cfg(feature = "replay")]
+    pub fn record_to_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.record_dir = Some(dir.into());
+        self
+    }
+
+    /// Serve the responses recorded in the given directory instead of using the network.
+    ///
+    /// See [`Client::replay_from_dir`].
+    #[cfg(feature = "replay")]
+    pub fn replay_from_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.replay_dir = Some(dir.into());
+        self
+    }
+
+    /// Build the [`Client`].
+    pub fn build(mut self) -> Result<Client, Error> {
+        let client = match self.reqwest_client.take() {
+            Some(client) => {
+                #[cfg(not(target_arch = "wasm32"))]
+                if self.cookie_store == Some(true) && self.cookie_jar.is_none() {
+                    return Err(Error::InvalidConfig(
+                        "a cookie store cannot be added to a preconfigured reqwest client, use a cookie provider instead".into(),
+                    ));
+                }
+                client
+            }
+            None => self.build_reqwest_client()?,
+        };
+
+        let transport = match self.transport.take() {
+            Some(transport) => transport,
+            None => Arc::new(ReqwestTransport::new(client.clone())),
+        };
+        #[cfg(feature = "replay")]
+        let transport: Arc<dyn Transport> = match self.replay_dir.take() {
+            Some(dir) => Arc::new(ReplayTransport::new(dir)),
+            None => transport,
+        };
+        #[cfg(feature = "replay")]
+        let transport: Arc<dyn Transport> = match self.record_dir.take() {
+            Some(dir) => Arc::new(RecordingTransport::new(transport, dir)),
+            None => transport,
+        };
+
+        let sleep = self.sleep.take().or_else(SleepFn::default_timer);
+        if self.max_retries > 0 && sleep.is_none() {
+            return Err(Error::InvalidConfig(
+                "retrying rate-limited requests needs the \"tokio\" feature or a sleep function"
+                    .into(),
+            ));
+        }
+
+        let base_url = self
+            .base_url
+            .as_deref()
+            .unwrap_or(DEFAULT_BASE_URL)
+            .trim_end_matches('/')
+            .to_string();
+
+        Ok(Client {
+            client,
+            transport,
+            max_retries: self.max_retries,
+            base_url,
+            default_model: self.default_model.unwrap_or(DEFAULT_MODEL),
+            default_headers: self.default_headers,
+            vqd_hash_solver: self.vqd_hash_solver,
+            request_middleware: self.request_middleware,
+            sleep,
+            observer: self.observer,
+            resume_on_disconnect: self.resume_on_disconnect,
+            handshake_timeout: self.handshake_timeout,
+            #[cfg(feature = "tokio")]
+            stream_idle_timeout: self.stream_idle_timeout,
+            require_done: self.require_done,
+            retry_empty_responses: self.retry_empty_responses,
+            sse_buffer_capacity: self.sse_buffer_capacity,
+            content_filter: self.content_filter,
+            filter_responses: self.filter_responses,
+            shutdown: CancellationToken::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            cookie_jar: self.cookie_jar,
+        })
+    }
+
+    /// Build the reqwest client from the http options.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn build_reqwest_client(&mut self) -> Result<reqwest::Client, Error> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(self.user_agent.as_deref().unwrap_or(USER_AGENT_STR));
+        if self.http1_title_case_headers.unwrap_or(true) {
+            builder = builder.http1_title_case_headers();
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if self.no_proxy {
+            builder = builder.no_proxy();
+        }
+        for proxy in self.proxies.drain(..) {
+            builder = builder.proxy(proxy);
+        }
+        if let Some(jar) = self.cookie_jar.as_ref() {
+            builder = builder.cookie_provider(jar.clone());
+        } else if self.cookie_store.unwrap_or(true) {
+            let jar = Arc::new(Jar::default());
+            builder = builder.cookie_provider(jar.clone());
+            self.cookie_jar = Some(jar);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// Build the reqwest client from the http options.
+    ///
+    /// The browser controls the user agent, connections, and proxies,
+    /// so those options are ignored.
+    #[cfg(target_arch = "wasm32")]
+    fn build_reqwest_client(&mut self) -> Result<reqwest::Client, Error> {
+        let _ = (
+            &self.user_agent,
+            self.timeout,
+            self.connect_timeout,
+            self.pool_max_idle_per_host,
+        );
+        Ok(reqwest::Client::builder().build()?)
+    }
+}
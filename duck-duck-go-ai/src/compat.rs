@@ -0,0 +1,2 @@
+/// Conversions to and from the OpenAI chat completions format.
+pub mod openai;
@@ -0,0 +1,291 @@
+use crate::model::ChatResponseMessage;
+use crate::Attachment;
+use crate::ChatMessage;
+use crate::ChatRequest;
+use crate::ChatResponseStream;
+use crate::Error;
+use crate::Model;
+use crate::Role;
+use std::pin::Pin;
+use std::task::ready;
+use std::task::Context;
+use std::task::Poll;
+use tokio_stream::Stream;
+
+/// An OpenAI chat completion request.
+///
+/// Only the fields that can be mapped are included.
+/// Unknown fields are ignored when deserializing.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ChatCompletionRequest {
+    /// The model.
+    pub model: String,
+
+    /// The messages.
+    pub messages: Vec<ChatCompletionMessage>,
+
+    /// Whether the response should be streamed.
+    #[serde(default)]
+    pub stream: bool,
+}
+
+/// An OpenAI chat completion message.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ChatCompletionMessage {
+    /// The role.
+    pub role: String,
+
+    /// The message content.
+    ///
+    /// This may be null, like for assistant messages with only tool calls.
+    #[serde(default)]
+    pub content: Option<ChatCompletionContent>,
+}
+
+/// The content of an OpenAI chat completion message.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum ChatCompletionContent {
+    /// Plain text.
+    Text(String),
+
+    /// A list of content parts.
+    Parts(Vec<ChatCompletionContentPart>),
+}
+
+/// A part of the content of an OpenAI chat completion message.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatCompletionContentPart {
+    /// Text.
+    Text {
+        /// The text.
+        text: String,
+    },
+
+    /// An image.
+    ImageUrl {
+        /// The image url.
+        image_url: ChatCompletionImageUrl,
+    },
+
+    /// A part type that cannot be mapped, like audio.
+    #[serde(other)]
+    Other,
+}
+
+/// The image of an OpenAI image content part.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ChatCompletionImageUrl {
+    /// The url.
+    ///
+    /// Only base64 data urls can be mapped to attachments.
+    pub url: String,
+}
+
+impl From<ChatCompletionMessage> for ChatMessage {
+    fn from(message: ChatCompletionMessage) -> Self {
+        // "developer" is the newer name for "system".
+        let role = match message.role.as_str() {
+            "developer" => Role::System,
+            _ => Role::from(message.role),
+        };
+
+        let mut content = String::new();
+        let mut attachments = Vec::new();
+        match message.content {
+            Some(ChatCompletionContent::Text(text)) => content = text,
+            Some(ChatCompletionContent::Parts(parts)) => {
+                for part in parts {
+                    match part {
+                        ChatCompletionContentPart::Text { text } => {
+                            if !content.is_empty() {
+                                content.push('\n');
+                            }
+                            content.push_str(&text);
+                        }
+                        ChatCompletionContentPart::ImageUrl { image_url } => {
+                            if let Some(attachment) = parse_data_url(&image_url.url) {
+                                attachments.push(attachment);
+                            }
+                        }
+                        ChatCompletionContentPart::Other => {}
+                    }
+                }
+            }
+            None => {}
+        }
+
+        Self {
+            role,
+            content,
+            attachments,
+        }
+    }
+}
+
+/// Parse a base64 data url into an attachment.
+fn parse_data_url(url: &str) -> Option<Attachment> {
+    let (mime_type, data) = url.strip_prefix("data:")?.split_once(";base64,")?;
+
+    Some(Attachment {
+        mime_type: mime_type.into(),
+        data: data.into(),
+    })
+}
+
+impl From<ChatCompletionRequest> for ChatRequest {
+    fn from(request: ChatCompletionRequest) -> Self {
+        let mut chat_request =
+            ChatRequest::from_messages(request.messages.into_iter().map(Into::into).collect());
+        chat_request.model = Model::from(request.model);
+        chat_request
+    }
+}
+
+/// An OpenAI streaming chat completion chunk.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ChatCompletionChunk {
+    /// The id of the completion.
+    pub id: String,
+
+    /// The object type, which is always "chat.completion.chunk".
+    pub object: String,
+
+    /// The creation time.
+    pub created: u64,
+
+    /// The model that generated the completion.
+    pub model: String,
+
+    /// The choices.
+    ///
+    /// This always has exactly one choice.
+    pub choices: Vec<ChatCompletionChunkChoice>,
+}
+
+impl ChatCompletionChunk {
+    /// Make a new chunk with a single choice.
+    fn new(id: String, created: u64, model: String, choice: ChatCompletionChunkChoice) -> Self {
+        Self {
+            id,
+            object: "chat.completion.chunk".into(),
+            created,
+            model,
+            choices: vec![choice],
+        }
+    }
+}
+
+impl From<ChatResponseMessage> for ChatCompletionChunk {
+    fn from(message: ChatResponseMessage) -> Self {
+        let choice = ChatCompletionChunkChoice {
+            index: 0,
+            delta: ChatCompletionDelta {
+                role: message.role.map(|role| role.to_string()),
+                content: message.message,
+            },
+            finish_reason: None,
+        };
+
+        Self::new(message.id, message.created, message.model, choice)
+    }
+}
+
+/// A choice in an OpenAI streaming chat completion chunk.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ChatCompletionChunkChoice {
+    /// The index of this choice.
+    pub index: u32,
+
+    /// The new content.
+    pub delta: ChatCompletionDelta,
+
+    /// Why the completion finished, if it did.
+    pub finish_reason: Option<String>,
+}
+
+/// The new content in an OpenAI streaming chat completion chunk.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ChatCompletionDelta {
+    /// The role.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+
+    /// The content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// A stream of OpenAI-style server-sent events.
+///
+/// Each item is a complete event, including the trailing blank line.
+/// After the response ends, a chunk with a "stop" finish reason is sent,
+/// followed by the `[DONE]` sentinel.
+#[derive(Debug)]
+pub struct OpenAiSseStream {
+    stream: ChatResponseStream,
+    last: Option<(String, u64, String)>,
+    state: OpenAiSseStreamState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpenAiSseStreamState {
+    Streaming,
+    SendDone,
+    Done,
+}
+
+impl OpenAiSseStream {
+    /// Make a new [`OpenAiSseStream`] from a response stream.
+    pub fn new(stream: ChatResponseStream) -> Self {
+        Self {
+            stream,
+            last: None,
+            state: OpenAiSseStreamState::Streaming,
+        }
+    }
+}
+
+impl Stream for OpenAiSseStream {
+    type Item = Result<String, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.state {
+            OpenAiSseStreamState::Streaming => {}
+            OpenAiSseStreamState::SendDone => {
+                self.state = OpenAiSseStreamState::Done;
+                return Poll::Ready(Some(Ok("data: [DONE]\n\n".into())));
+            }
+            OpenAiSseStreamState::Done => return Poll::Ready(None),
+        }
+
+        let chunk = match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+            Some(Ok(message)) => {
+                self.last = Some((message.id.clone(), message.created, message.model.clone()));
+                ChatCompletionChunk::from(message)
+            }
+            Some(Err(error)) => return Poll::Ready(Some(Err(error))),
+            None => {
+                self.state = OpenAiSseStreamState::SendDone;
+                let (id, created, model) = self.last.take().unwrap_or_default();
+                let choice = ChatCompletionChunkChoice {
+                    index: 0,
+                    delta: ChatCompletionDelta {
+                        role: None,
+                        content: None,
+                    },
+                    finish_reason: Some("stop".into()),
+                };
+                ChatCompletionChunk::new(id, created, model, choice)
+            }
+        };
+
+        let data = match serde_json::to_string(&chunk) {
+            Ok(data) => data,
+            Err(error) => return Poll::Ready(Some(Err(Error::InvalidSseEventData(error)))),
+        };
+
+        Poll::Ready(Some(Ok(format!("data: {data}\n\n"))))
+    }
+}
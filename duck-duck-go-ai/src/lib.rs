@@ -1,25 +1,63 @@
+#[cfg(all(feature = "blocking", not(target_arch = "wasm32")))]
+pub mod blocking;
 mod client;
+pub mod compat;
 pub mod model;
+mod observer;
+pub mod prompt;
+mod sse;
+pub mod transport;
 
 pub use self::client::Client;
+pub use self::client::ClientBuilder;
+pub use self::client::SleepFuture;
+pub use self::model::Attachment;
+pub use self::model::ChatEvent;
+pub use self::model::ChatEventStream;
 pub use self::model::ChatMessage;
 pub use self::model::ChatRequest;
 pub use self::model::ChatResponseStream;
+pub use self::model::CollectedResponse;
+pub use self::model::FilterAction;
+pub use self::model::Model;
+pub use self::model::ProgressEvent;
+pub use self::model::Role;
+pub use self::model::StatusInfo;
+pub use self::observer::Observer;
+pub use self::transport::Transport;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::time::Duration;
+use std::time::SystemTime;
 
 /// The library error type
+///
+/// More variants may be added in the future.
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error {
     /// A http error occured
+    ///
+    /// This is used for http errors that are not covered by a more specific variant.
     #[error("http error")]
-    Reqwest(#[from] reqwest::Error),
+    Reqwest(#[source] reqwest::Error),
+
+    /// Failed to connect to the server
+    #[error("failed to connect")]
+    Connect(#[source] reqwest::Error),
+
+    /// Failed to decode a response body
+    #[error("failed to decode response body")]
+    Decode(#[source] reqwest::Error),
 
     /// Failed to join a tokio task
+    #[cfg(feature = "tokio")]
     #[error("bad tokio join")]
     TokioJoin(#[from] tokio::task::JoinError),
 
     /// An sse event was invalid
     #[error("invalid sse event")]
-    InvalidSseEvent(#[from] nd_tokio_sse_codec::SseCodecError),
+    InvalidSseEvent(#[source] std::io::Error),
 
     /// An sse event is missing data
     #[error("sse event missing data")]
@@ -29,6 +67,10 @@ pub enum Error {
     #[error("invalid sse json data")]
     InvalidSseEventData(#[source] serde_json::Error),
 
+    /// An io error
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+
     /// The stream was empty
     #[error("stream empty")]
     StreamEmpty,
@@ -36,21 +78,829 @@ pub enum Error {
     /// Missing Vqd
     #[error("missing vqd")]
     MissingVqd,
+
+    /// A chat request has a malformed conversation
+    ///
+    /// This contains a description of the problem.
+    /// See [`ChatRequest::validate`].
+    #[error("invalid conversation: {0}")]
+    InvalidConversation(String),
+
+    /// A chat response was not an event stream
+    ///
+    /// This usually means that the request was blocked and an html page was returned instead.
+    #[error("expected an event stream, got \"{content_type}\"")]
+    UnexpectedContentType {
+        /// The content type of the response
+        content_type: String,
+
+        /// The start of the response body
+        body_snippet: String,
+    },
+
+    /// A prompt template is malformed or uses a missing variable
+    ///
+    /// This contains a description of the problem.
+    #[error("invalid template: {0}")]
+    InvalidTemplate(String),
+
+    /// The server returned a vqd that is empty or malformed
+    ///
+    /// This contains the raw header value.
+    #[error("invalid vqd \"{0}\"")]
+    InvalidVqd(String),
+
+    /// A stream ended without the `[DONE]` sentinel
+    ///
+    /// This usually means that the connection was dropped and the response may be truncated.
+    /// See [`ChatResponseStream::with_require_done`].
+    #[error("stream ended without a [DONE] sentinel")]
+    IncompleteStream,
+
+    /// The client builder has conflicting options
+    ///
+    /// This contains a description of the problem.
+    #[error("invalid client config: {0}")]
+    InvalidConfig(String),
+
+    /// The client was shut down
+    ///
+    /// See [`Client::shutdown`].
+    #[error("client was shut down")]
+    ShutDown,
+
+    /// No event arrived on a stream within the idle timeout
+    #[error("stream idle timeout")]
+    IdleTimeout,
+
+    /// A stream did not finish within its deadline
+    #[error("stream deadline exceeded")]
+    DeadlineExceeded,
+
+    /// A stream failed after some content was received
+    #[error("stream failed after receiving partial content")]
+    PartialResponse {
+        /// The content received before the error
+        content: String,
+
+        /// The error
+        #[source]
+        source: Box<Error>,
+    },
+
+    /// The server sent an error event
+    #[error("server error: {message}")]
+    ServerError {
+        /// The error message
+        message: String,
+
+        /// The error type, like "ERR_CONVERSATION_LIMIT"
+        ty: Option<String>,
+    },
+
+    /// The model is known to no longer work
+    ///
+    /// See [`Model::replacement`] for a suggested replacement.
+    #[error("model \"{0}\" is retired{}", retired_model_hint(.0))]
+    RetiredModel(String),
+
+    /// The server responded with an unsuccessful status
+    #[error("http status {status}: {body}")]
+    Http {
+        /// The status
+        status: StatusCode,
+
+        /// The response body
+        body: String,
+    },
+
+    /// The server responded with a different model than the one requested
+    #[error("requested model \"{requested}\", but was served \"{served}\"")]
+    ModelMismatch {
+        /// The requested model
+        requested: String,
+
+        /// The model that was served
+        served: String,
+    },
+
+    /// Content was rejected by the content filter
+    #[error("content was rejected by the content filter")]
+    Filtered,
+
+    /// The last message is not an assistant reply to a user message
+    #[error("nothing to regenerate")]
+    NothingToRegenerate,
+
+    /// The server rate limited the request
+    ///
+    /// Only the headers are used; the body of the rate limited response is ignored.
+    #[error("rate limited")]
+    RateLimited {
+        /// How long the server asked to wait before retrying
+        ///
+        /// This is taken from the "Retry-After" header, either as a number of seconds or as an http date.
+        /// An http date is always `None` on wasm.
+        retry_after: Option<Duration>,
+
+        /// An estimate of when the rate limit resets
+        ///
+        /// This is taken from the "Retry-After" header,
+        /// or from a "RateLimit-Reset" or "X-RateLimit-Reset" header.
+        /// It is always `None` on wasm.
+        reset_at: Option<SystemTime>,
+
+        /// The headers of the rate limited response
+        headers: Box<HeaderMap>,
+    },
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        if error.is_connect() {
+            return Self::Connect(error);
+        }
+
+        if error.is_decode() {
+            return Self::Decode(error);
+        }
+
+        Self::Reqwest(error)
+    }
 }
 
-#[cfg(test)]
+impl Error {
+    /// Check whether this error means that DuckDuckGo is refusing requests from this client.
+    ///
+    /// This includes rate limits, anti-bot pages, bad vqd tokens, and forbidden responses.
+    /// Retrying soon is unlikely to help.
+    pub fn is_blocked(&self) -> bool {
+        match self {
+            Self::RateLimited { .. }
+            | Self::MissingVqd
+            | Self::InvalidVqd(_)
+            | Self::UnexpectedContentType { .. } => true,
+            Self::Http { status, .. } => matches!(
+                *status,
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::IM_A_TEAPOT
+            ),
+            Self::PartialResponse { source, .. } => source.is_blocked(),
+            _ => false,
+        }
+    }
+
+    /// Check whether this error means that DuckDuckGo could not be reached or failed on its side.
+    ///
+    /// This includes connection failures, timeouts, and server errors.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Self::Connect(_) | Self::IdleTimeout | Self::DeadlineExceeded => true,
+            Self::Reqwest(error) => error.is_timeout(),
+            Self::Http { status, .. } => status.is_server_error(),
+            Self::PartialResponse { source, .. } => source.is_unavailable(),
+            _ => false,
+        }
+    }
+
+    /// Wrap an error that occured mid-stream, keeping the content received before it.
+    ///
+    /// If no content was received, the error is returned as-is.
+    pub(crate) fn with_partial_content(self, content: String) -> Self {
+        if content.is_empty() {
+            return self;
+        }
+
+        Self::PartialResponse {
+            content,
+            source: Box::new(self),
+        }
+    }
+}
+
+/// Get the suffix of a [`Error::RetiredModel`] message that suggests a replacement.
+fn retired_model_hint(model: &str) -> String {
+    match Model::from(model).replacement() {
+        Some(replacement) => format!(", try \"{replacement}\" instead"),
+        None => String::new(),
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
 mod test {
     use super::*;
+    use futures_util::stream::FusedStream;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio_stream::StreamExt;
+    use tokio_util::sync::CancellationToken;
+
+    /// Make a raw http response.
+    fn http_response(status: &str, content_type: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nx-vqd-4: mock-vqd\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// Make a raw http response for an sse stream.
+    fn sse_response(body: &str) -> String {
+        http_response("200 OK", "text/event-stream", body)
+    }
+
+    /// Read an http request, returning the request head.
+    async fn read_request(socket: &mut tokio::net::TcpStream) -> String {
+        let mut buffer = Vec::new();
+        let head_end = loop {
+            let mut chunk = [0; 1024];
+            let n = socket.read(&mut chunk).await.expect("failed to read");
+            assert!(n != 0, "unexpected eof");
+            buffer.extend_from_slice(&chunk[..n]);
+
+            if let Some(index) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                break index + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&buffer[..head_end]).into_owned();
+
+        let content_length: usize = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map(|(_, value)| value.trim().parse().expect("invalid content length"))
+            .unwrap_or(0);
+        while buffer.len() - head_end < content_length {
+            let mut chunk = [0; 1024];
+            let n = socket.read(&mut chunk).await.expect("failed to read");
+            assert!(n != 0, "unexpected eof");
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+
+        head
+    }
+
+    /// Spawn a mock server.
+    ///
+    /// The status handshake always succeeds,
+    /// and chat requests are answered with the given raw http response.
+    /// Returns the base url.
+    async fn spawn_mock_server(chat_response: String) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let address = listener.local_addr().expect("failed to get address");
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let chat_response = chat_response.clone();
+                tokio::spawn(async move {
+                    let head = read_request(&mut socket).await;
+                    let response = if head.starts_with("GET /duckchat/v1/status") {
+                        http_response("200 OK", "application/json", "{\"status\":\"0\"}")
+                    } else {
+                        chat_response
+                    };
+                    socket
+                        .write_all(response.as_bytes())
+                        .await
+                        .expect("failed to write");
+                    let _ = socket.shutdown().await.is_ok();
+                });
+            }
+        });
+
+        format!("http://{address}")
+    }
+
+    /// Make a client that talks to a mock server.
+    async fn mock_client(chat_response: String) -> Client {
+        let base_url = spawn_mock_server(chat_response).await;
+        Client::builder()
+            .base_url(&base_url)
+            .build()
+            .expect("failed to build client")
+    }
+
+    /// Make a raw http response for an sse stream that is cut off after the given body.
+    fn truncated_sse_response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: {}\r\nx-vqd-4: mock-vqd\r\nConnection: close\r\n\r\n{body}",
+            body.len() + 1024
+        )
+    }
+
+    /// Spawn a mock server that answers chat requests with the given responses, in order.
+    ///
+    /// Status requests get a "mock-vqd" vqd.
+    /// Once the responses run out, the last one is repeated.
+    /// Returns the base url, the heads of all requests received so far, and the number of chat requests.
+    async fn spawn_scripted_server(
+        chat_responses: Vec<String>,
+    ) -> (String, Arc<std::sync::Mutex<Vec<String>>>, Arc<AtomicUsize>) {
+        let status_response = http_response("200 OK", "application/json", "{\"status\":\"0\"}");
+        spawn_scripted_server_with_status(status_response, chat_responses).await
+    }
+
+    /// Spawn a scripted mock server with the given response for status requests.
+    async fn spawn_scripted_server_with_status(
+        status_response: String,
+        chat_responses: Vec<String>,
+    ) -> (String, Arc<std::sync::Mutex<Vec<String>>>, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let address = listener.local_addr().expect("failed to get address");
+        let heads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let chats = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn({
+            let heads = heads.clone();
+            let chats = chats.clone();
+            async move {
+                let mut chat_responses = chat_responses.into_iter();
+                let mut last_response = String::new();
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    let head = read_request(&mut socket).await;
+                    let response = if head.starts_with("GET /duckchat/v1/status") {
+                        status_response.clone()
+                    } else {
+                        chats.fetch_add(1, Ordering::SeqCst);
+                        if let Some(response) = chat_responses.next() {
+                            last_response = response;
+                        }
+                        last_response.clone()
+                    };
+                    heads.lock().expect("poisoned").push(head);
+                    socket
+                        .write_all(response.as_bytes())
+                        .await
+                        .expect("failed to write");
+                    let _ = socket.shutdown().await.is_ok();
+                }
+            }
+        });
+
+        (format!("http://{address}"), heads, chats)
+    }
+
+    /// Spawn a mock server that sends one chat event, then never ends the response.
+    ///
+    /// This returns the base url.
+    async fn spawn_stalled_server() -> String {
+        let status_response = http_response("200 OK", "application/json", "{}");
+        spawn_stalled_server_with_status(Some(status_response)).await
+    }
+
+    /// Spawn a stalled mock server with the given response for status requests.
+    ///
+    /// If there is no status response, status requests are never answered either.
+    async fn spawn_stalled_server_with_status(status_response: Option<String>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let address = listener.local_addr().expect("failed to get address");
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let status_response = status_response.clone();
+                tokio::spawn(async move {
+                    let head = read_request(&mut socket).await;
+                    if head.starts_with("GET /duckchat/v1/status") {
+                        let Some(response) = status_response else {
+                            std::future::pending::<()>().await;
+                            return;
+                        };
+                        socket
+                            .write_all(response.as_bytes())
+                            .await
+                            .expect("failed to write");
+                        return;
+                    }
+
+                    // Send one event, then keep the connection open forever.
+                    let event = message_event("Hello");
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nx-vqd-4: mock-vqd\r\n\r\n{event}"
+                    );
+                    socket
+                        .write_all(response.as_bytes())
+                        .await
+                        .expect("failed to write");
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+
+        format!("http://{address}")
+    }
+
+    /// Make an sse event for a response message.
+    fn message_event(content: &str) -> String {
+        let message = serde_json::json!({
+            "role": "assistant",
+            "message": content,
+            "created": 1,
+            "id": "mock-id",
+            "action": "success",
+            "model": "gpt-4o-mini",
+        });
+        format!("data: {message}\n\n")
+    }
+
+    #[test]
+    fn model_round_trip() {
+        for model in [
+            Model::Gpt4OMini,
+            Model::Claude35Sonnet,
+            Model::MetaLlama31_70B,
+            Model::Other("not-a-real-model".into()),
+        ] {
+            let json = serde_json::to_string(&model).expect("failed to serialize");
+            assert_eq!(json, format!("\"{}\"", model.as_str()));
+
+            let parsed: Model = serde_json::from_str(&json).expect("failed to deserialize");
+            assert_eq!(parsed, model);
+            assert_eq!(model.to_string().parse::<Model>(), Ok(model));
+        }
+    }
+
+    #[test]
+    fn role_round_trip() {
+        for role in [
+            Role::User,
+            Role::Assistant,
+            Role::System,
+            Role::Other("tool".into()),
+        ] {
+            let json = serde_json::to_string(&role).expect("failed to serialize");
+            assert_eq!(json, format!("\"{}\"", role.as_str()));
+
+            let parsed: Role = serde_json::from_str(&json).expect("failed to deserialize");
+            assert_eq!(parsed, role);
+        }
+    }
+
+    #[test]
+    fn response_message_metadata() {
+        let event = message_event("Hello");
+        let data = event
+            .strip_prefix("data: ")
+            .expect("missing data prefix")
+            .trim_end();
+        let message: model::ChatResponseMessage =
+            serde_json::from_str(data).expect("failed to parse message");
+        assert_eq!(message.id(), "mock-id");
+        assert_eq!(
+            message.created_time(),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn message_role_predicates() {
+        assert!(ChatMessage::user("Hello!").is_user());
+        assert!(ChatMessage::assistant("Hi!").is_assistant());
+        assert!(ChatMessage::system("Be nice.").is_system());
+        assert!(!ChatMessage::user("Hello!").is_assistant());
+
+        let message = ChatMessage {
+            role: Role::from("Assistant"),
+            content: "Hi!".into(),
+            attachments: Vec::new(),
+        };
+        assert!(message.is_assistant());
+        assert!(!message.is_user());
+    }
+
+    #[test]
+    fn request_builder() {
+        let mut request = ChatRequest::new(Model::Claude3Haiku);
+        request
+            .push_user("Hello!")
+            .push_assistant("Hi!")
+            .push_user("How are you?");
+        assert_eq!(request.model, Model::Claude3Haiku);
+        assert_eq!(request.messages.len(), 3);
+        assert_eq!(request.messages[1], ChatMessage::assistant("Hi!"));
+        assert_eq!(request.vqd(), None);
+
+        assert_eq!(ChatRequest::default().model, Model::Gpt4OMini);
+    }
+
+    #[test]
+    fn streams_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<ChatResponseStream>();
+        assert_send_sync::<ChatEventStream>();
+        assert_send_sync::<crate::compat::openai::OpenAiSseStream>();
+    }
+
+    #[test]
+    fn validate_conversation() {
+        let mut request = ChatRequest::new(Model::Gpt4OMini);
+        assert!(matches!(
+            request.validate(),
+            Err(Error::InvalidConversation(_))
+        ));
+
+        request.set_system_prompt("Be brief.".into());
+        request.push_user("Hello!");
+        request.validate().expect("conversation should be valid");
+
+        request.push_assistant("Hi!");
+        assert!(matches!(
+            request.validate(),
+            Err(Error::InvalidConversation(_))
+        ));
+
+        request.push_assistant("How are you?");
+        let error = request.validate().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid conversation: expected a \"user\" message at index 3, found \"assistant\""
+        );
+    }
+
+    #[test]
+    fn validate_retired_model() {
+        let mut request = ChatRequest::new("gpt-4");
+        request.push_user("Hello!");
+        let error = request.validate().unwrap_err();
+        assert!(matches!(error, Error::RetiredModel(_)), "{error:?}");
+        assert_eq!(
+            error.to_string(),
+            "model \"gpt-4\" is retired, try \"gpt-4o\" instead"
+        );
+        assert_eq!(Model::from("gpt-4").replacement(), Some(Model::Gpt4O));
+        assert_eq!(Model::Gpt4OMini.replacement(), None);
+    }
+
+    #[test]
+    fn prompt_builder() {
+        let builder = prompt::PromptBuilder::new()
+            .system("You are {persona}.")
+            .user("Translate {{{text}}} to {language}.")
+            .var("persona", "a translator")
+            .var("text", "{hello}")
+            .var("language", "French");
+        let request = builder
+            .build_request(Model::Claude3Haiku)
+            .expect("failed to build request");
+        assert_eq!(request.model, Model::Claude3Haiku);
+        assert_eq!(
+            request.messages,
+            [
+                ChatMessage::system("You are a translator."),
+                ChatMessage::user("Translate {{hello}} to French."),
+            ]
+        );
+
+        let error = builder
+            .clone()
+            .user("{missing}")
+            .build_messages()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid template: missing variable \"missing\""
+        );
+        assert!(matches!(
+            builder.clone().user("{open").build_messages(),
+            Err(Error::InvalidTemplate(_))
+        ));
+        assert!(matches!(
+            builder.user("close}").build_messages(),
+            Err(Error::InvalidTemplate(_))
+        ));
+    }
+
+    #[test]
+    fn transcript_export() {
+        let mut request = ChatRequest::new(Model::Gpt4OMini);
+        request
+            .push_user("Hello!")
+            .push_assistant("Hi! *waves*")
+            .messages
+            .push(ChatMessage {
+                role: Role::Other("tool_call".into()),
+                content: "{}".into(),
+                attachments: Vec::new(),
+            });
+
+        assert_eq!(
+            request.to_markdown(),
+            "**User:** Hello!\n\n**Assistant:** Hi! *waves*\n\n**tool\\_call:** {}"
+        );
+        assert_eq!(
+            request.to_plaintext(),
+            "User: Hello!\n\nAssistant: Hi! *waves*\n\ntool_call: {}"
+        );
+    }
+
+    #[test]
+    fn generation_params() {
+        let mut request = ChatRequest::new(Model::Gpt4OMini);
+        request.push_user("Hello!");
+        let wire = serde_json::to_value(request.wire()).expect("failed to serialize");
+        assert_eq!(
+            wire,
+            serde_json::json!({
+                "messages": [{ "role": "user", "content": "Hello!" }],
+                "model": "gpt-4o-mini",
+            })
+        );
+
+        request.set_temperature(Some(0.5)).set_max_tokens(Some(16));
+        let wire = serde_json::to_value(request.wire()).expect("failed to serialize");
+        assert_eq!(wire["temperature"], 0.5);
+        assert_eq!(wire["max_tokens"], 16);
+        assert!(wire.get("top_p").is_none());
+
+        let mut other = request.clone();
+        assert_eq!(other, request);
+        other.set_temperature(Some(0.25));
+        assert_ne!(other, request);
+    }
+
+    #[test]
+    fn image_attachments() {
+        let mut request = ChatRequest::new(Model::Gpt4OMini);
+        request.messages.push(ChatMessage::user_with_image(
+            "What is this?",
+            b"png",
+            "image/png",
+        ));
+        let wire = serde_json::to_value(request.wire()).expect("failed to serialize");
+        assert_eq!(
+            wire["messages"],
+            serde_json::json!([{
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "What is this?" },
+                    { "type": "image", "mimeType": "image/png", "image": "data:image/png;base64,cG5n" },
+                ],
+            }])
+        );
+        request.validate().expect("request should be valid");
+
+        let mut reply = ChatMessage::assistant("A picture.");
+        reply.attachments.push(Attachment::new(b"png", "image/png"));
+        request.messages.push(reply);
+        request.push_user("Thanks!");
+        assert!(matches!(
+            request.validate(),
+            Err(Error::InvalidConversation(_))
+        ));
+
+        // A leading system message is checked too.
+        let mut system = ChatMessage::system("Be brief.");
+        system
+            .attachments
+            .push(Attachment::new(b"png", "image/png"));
+        let mut request = ChatRequest::from_messages(vec![system, ChatMessage::user("Hello!")]);
+        match request.validate() {
+            Err(Error::InvalidConversation(message)) => {
+                assert!(message.contains("at index 0"), "{message}");
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+        request.messages[0].attachments.clear();
+        request.validate().expect("request should be valid");
+    }
+
+    #[test]
+    fn request_from_iter() {
+        let mut request: ChatRequest = ["Hello!", "Hi!"]
+            .into_iter()
+            .map(ChatMessage::user)
+            .collect();
+        assert_eq!(request.model, Model::Gpt4OMini);
+        assert_eq!(request.vqd(), None);
+
+        request.extend([
+            ChatMessage::assistant("Hello!"),
+            ChatMessage::user("How are you?"),
+        ]);
+        assert_eq!(request.messages.len(), 4);
+        assert_eq!(request.messages[3], ChatMessage::user("How are you?"));
+    }
+
+    #[test]
+    fn cookies_round_trip() {
+        let client = Client::builder()
+            .base_url("http://127.0.0.1")
+            .build()
+            .expect("failed to build client");
+        assert_eq!(client.cookies(), None);
+
+        client.set_cookies("a=1; b=2");
+        assert_eq!(client.cookies().as_deref(), Some("a=1; b=2"));
+    }
+
+    #[test]
+    fn cookie_provider() {
+        // A jar shared with a preconfigured client is used for cookies.
+        let jar = Arc::new(reqwest::cookie::Jar::default());
+        let reqwest_client = reqwest::Client::builder()
+            .cookie_provider(jar.clone())
+            .build()
+            .expect("failed to build reqwest client");
+        let client = Client::builder()
+            .base_url("http://127.0.0.1")
+            .reqwest_client(reqwest_client.clone())
+            .cookie_provider(jar.clone())
+            .build()
+            .expect("failed to build client");
+        client.set_cookies("a=1");
+        assert_eq!(client.cookies().as_deref(), Some("a=1"));
+
+        // A cookie store cannot be added to a preconfigured client.
+        let error = Client::builder()
+            .reqwest_client(reqwest_client.clone())
+            .cookie_store(true)
+            .build()
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidConfig(_)), "{error:?}");
+
+        // Without a cookie store, there are no cookies.
+        let client = Client::builder()
+            .base_url("http://127.0.0.1")
+            .reqwest_client(reqwest_client)
+            .build()
+            .expect("failed to build client");
+        client.set_cookies("a=1");
+        assert_eq!(client.cookies(), None);
+
+        // A jar can also be shared between clients that this builder makes.
+        let client = Client::builder()
+            .base_url("http://127.0.0.1")
+            .cookie_provider(jar)
+            .build()
+            .expect("failed to build client");
+        assert_eq!(client.cookies().as_deref(), Some("a=1"));
+    }
+
+    #[test]
+    fn system_prompt_is_folded() {
+        let mut request = ChatRequest::from_messages(vec![ChatMessage::user("Hello!")]);
+        request.set_system_prompt("Be brief.".into());
+        request.set_system_prompt("Be very brief.".into());
+        assert_eq!(request.messages.len(), 2);
+
+        let json = serde_json::to_value(request.wire()).expect("failed to serialize");
+        assert_eq!(
+            json["messages"],
+            serde_json::json!([{ "role": "user", "content": "Be very brief.\n\nHello!" }])
+        );
+    }
+
+    #[test]
+    fn truncate_to() {
+        let mut request = ChatRequest::from_messages(vec![
+            ChatMessage::system("1234"),
+            ChatMessage::user("1234"),
+            ChatMessage::assistant("1234"),
+            ChatMessage::user("1234"),
+            ChatMessage::assistant("1234"),
+            ChatMessage::user("1234"),
+            ChatMessage::assistant("1234"),
+            ChatMessage::user("1234"),
+        ]);
+
+        let mut keep_first = request.clone();
+        keep_first.truncate_to(4, true);
+        assert_eq!(
+            keep_first.messages,
+            [
+                ChatMessage::system("1234"),
+                ChatMessage::user("1234"),
+                ChatMessage::assistant("1234"),
+                ChatMessage::user("1234"),
+            ]
+        );
+
+        request.truncate_to(3, false);
+        assert_eq!(
+            request.messages,
+            [ChatMessage::system("1234"), ChatMessage::user("1234")]
+        );
+        assert_eq!(request.estimated_tokens(), 2);
+
+        request.truncate_to(0, false);
+        assert_eq!(request.messages.len(), 2);
+    }
 
     #[tokio::test]
     async fn it_works() {
         let client = Client::new();
         let mut request = client.init_chat().await.expect("failed to init chat");
-        request.messages.push(ChatMessage {
-            role: "user".into(),
-            content: "Hello! How are you today?".into(),
-        });
-        request.model = "mistralai/Mixtral-8x7B-Instruct-v0.1".into();
+        request
+            .messages
+            .push(ChatMessage::user("Hello! How are you today?"));
+        request.model = Model::Mixtral8x7B;
 
         let mut stream = client
             .chat(&request)
@@ -67,10 +917,9 @@ mod test {
     async fn it_works_gpt() {
         let client = Client::new();
         let mut request = client.init_chat().await.expect("failed to init chat");
-        request.messages.push(ChatMessage {
-            role: "user".into(),
-            content: "Hello! How are you today?".into(),
-        });
+        request
+            .messages
+            .push(ChatMessage::user("Hello! How are you today?"));
 
         let mut stream = client
             .chat(&request)
@@ -82,4 +931,1807 @@ mod test {
             .expect("failed to collect message");
         dbg!(message);
     }
+
+    #[tokio::test]
+    async fn mock_stream() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        assert_eq!(request.vqd(), Some("mock-vqd"));
+        request.messages.push(ChatMessage::user("Hello!"));
+
+        let mut stream = client
+            .chat(&request)
+            .await
+            .expect("failed to send chat request");
+        assert_eq!(stream.vqd(), Some("mock-vqd"));
+        let message = stream
+            .collect_into_chat_message()
+            .await
+            .expect("failed to collect message");
+        assert_eq!(message.role, Role::Assistant);
+        assert_eq!(message.content, "Hello world!");
+        assert!(stream.is_terminated());
+        assert!(stream.is_complete());
+        assert_eq!(stream.last_message_id(), Some("mock-id"));
+        assert_eq!(stream.served_model(), Some("gpt-4o-mini"));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn mock_status() {
+        let client = mock_client(sse_response("data: [DONE]\n\n")).await;
+        let status = client.status().await.expect("failed to get status");
+        assert_eq!(status.status.as_deref(), Some("0"));
+        assert!(status.models.is_empty());
+        assert!(status.extra.is_empty());
+
+        let models = client.list_models().await.expect("failed to list models");
+        assert_eq!(models.len(), Model::KNOWN.len());
+    }
+
+    #[tokio::test]
+    async fn mock_default_model() {
+        let base_url = spawn_mock_server(sse_response("data: [DONE]\n\n")).await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .default_model("claude-3-haiku-20240307")
+            .build()
+            .expect("failed to build client");
+        let request = client.init_chat().await.expect("failed to init chat");
+        assert_eq!(request.model, Model::Claude3Haiku);
+    }
+
+    #[tokio::test]
+    async fn mock_resume_chat_default_model() {
+        let base_url = spawn_mock_server(sse_response("data: [DONE]\n\n")).await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .default_model("claude-3-haiku-20240307")
+            .build()
+            .expect("failed to build client");
+        let request = client
+            .resume_chat(vec![ChatMessage::user("hello")])
+            .await
+            .expect("failed to resume chat");
+        assert_eq!(request.model, Model::Claude3Haiku);
+        assert_eq!(request.vqd(), Some("mock-vqd"));
+    }
+
+    #[tokio::test]
+    async fn mock_shutdown() {
+        let client = Client::builder()
+            .base_url(&spawn_stalled_server().await)
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+        let stream = client
+            .chat(&request)
+            .await
+            .expect("failed to send chat request");
+        let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
+        let mut rx = stream.into_channel(1, move |message| {
+            let _ = finished_tx.send(message).is_ok();
+        });
+        let message = rx
+            .recv()
+            .await
+            .expect("channel closed")
+            .expect("failed to read message");
+        assert_eq!(message.message.as_deref(), Some("Hello"));
+
+        let clone = client.clone();
+        client.shutdown();
+        assert!(clone.is_shut_down());
+        // The reading task finishes, even though the server never ends the response.
+        assert!(rx.recv().await.is_none());
+        let message = finished_rx.await.expect("task did not finish");
+        assert_eq!(message, None);
+    }
+
+    #[tokio::test]
+    async fn mock_chat_with_cancel() {
+        let client = Client::builder()
+            .base_url(&spawn_stalled_server().await)
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let token = CancellationToken::new();
+        let mut stream = client
+            .chat_with_cancel(&request, token.clone())
+            .await
+            .expect("failed to chat");
+        let message = stream
+            .next()
+            .await
+            .expect("missing message")
+            .expect("failed to read message");
+        assert_eq!(message.message.as_deref(), Some("Hello"));
+        assert!(!stream.is_cancelled());
+
+        token.cancel();
+        // The stream ends, even though the server never ends the response.
+        let next = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("stream did not end");
+        assert!(next.is_none());
+        assert!(stream.is_cancelled());
+        assert!(stream.is_terminated());
+
+        // Tokens compose, so either one ends the stream.
+        let first = CancellationToken::new();
+        let second = CancellationToken::new();
+        let mut stream = client
+            .chat(&request)
+            .await
+            .expect("failed to chat")
+            .with_cancellation(first)
+            .with_cancellation(second.clone());
+        stream
+            .next()
+            .await
+            .expect("missing message")
+            .expect("failed to read message");
+        second.cancel();
+        let next = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("stream did not end");
+        assert!(next.is_none());
+        assert!(stream.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn mock_no_requests_after_shutdown() {
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let (base_url, heads, _chats) = spawn_scripted_server(vec![sse_response(&body)]).await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .retry_empty_responses(3)
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+        client.chat_once(&request).await.expect("failed to chat");
+        assert_eq!(heads.lock().expect("poisoned").len(), 2);
+
+        let clone = client.clone();
+        client.shutdown();
+        let error = clone.chat_once(&request).await.unwrap_err();
+        assert!(matches!(error, Error::ShutDown), "{error:?}");
+        let error = clone.init_chat().await.unwrap_err();
+        assert!(matches!(error, Error::ShutDown), "{error:?}");
+        assert_eq!(heads.lock().expect("poisoned").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn mock_resume_on_disconnect() {
+        let (base_url, heads, _chats) = spawn_scripted_server(vec![
+            truncated_sse_response(&message_event("Hello, this is a long")),
+            sse_response(&format!(
+                "{}data: [DONE]\n\n",
+                message_event("is a long answer.")
+            )),
+        ])
+        .await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .resume_on_disconnect(true)
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+        let message = client.chat_once(&request).await.expect("failed to chat");
+        assert_eq!(message.content, "Hello, this is a long answer.");
+        assert_eq!(heads.lock().expect("poisoned").len(), 3);
+
+        // A connection that drops before any content is just retried.
+        let (base_url, _heads, _chats) = spawn_scripted_server(vec![
+            truncated_sse_response(""),
+            sse_response(&format!("{}data: [DONE]\n\n", message_event("Hello"))),
+        ])
+        .await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .resume_on_disconnect(true)
+            .build()
+            .expect("failed to build client");
+        let message = client.chat_once(&request).await.expect("failed to chat");
+        assert_eq!(message.content, "Hello");
+    }
+
+    #[tokio::test]
+    async fn mock_resume_limit() {
+        let (base_url, heads, _chats) =
+            spawn_scripted_server(vec![truncated_sse_response(&message_event("Hello world"))])
+                .await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .resume_on_disconnect(true)
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+        let error = client.chat_once(&request).await.unwrap_err();
+        match error {
+            Error::PartialResponse { content, source } => {
+                // Repeated continuations are stripped as overlap.
+                assert_eq!(content, "Hello world");
+                assert!(!matches!(*source, Error::PartialResponse { .. }));
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+
+        // The handshake, the first attempt, and 3 resumes.
+        assert_eq!(heads.lock().expect("poisoned").len(), 5);
+    }
+
+    #[test]
+    fn strip_resume_overlap() {
+        use crate::client::strip_overlap;
+
+        assert_eq!(
+            strip_overlap("Grüße aus München", "aus München und Berlin"),
+            " und Berlin"
+        );
+        // Short overlaps are likely a coincidence.
+        assert_eq!(strip_overlap("Hello", "lo there"), "lo there");
+        // Lengths that split a character are skipped, instead of panicking.
+        assert_eq!(strip_overlap("aaaaaaaaaa", "üüüüü"), "üüüüü");
+        assert_eq!(strip_overlap("", "Hello"), "Hello");
+    }
+
+    #[tokio::test]
+    async fn mock_invalid_vqd_retry() {
+        let body = "{\"action\":\"error\",\"status\":400,\"type\":\"ERR_INVALID_VQD\"}";
+        let (base_url, heads, _chats) = spawn_scripted_server(vec![
+            http_response("400 Bad Request", "application/json", body),
+            sse_response(&format!("{}data: [DONE]\n\n", message_event("Hello"))),
+        ])
+        .await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .build()
+            .expect("failed to build client");
+        let mut request = ChatRequest::new(Model::Gpt4OMini);
+        request.set_vqd("stale-vqd".into());
+        request.push_user("Hello!");
+
+        let message = client.chat_once(&request).await.expect("failed to chat");
+        assert_eq!(message.content, "Hello");
+
+        let heads = heads.lock().expect("poisoned");
+        assert_eq!(heads.len(), 3);
+        assert!(heads[0].contains("x-vqd-4: stale-vqd"), "{}", heads[0]);
+        assert!(heads[1].starts_with("GET /duckchat/v1/status"));
+        assert!(heads[2].contains("x-vqd-4: mock-vqd"), "{}", heads[2]);
+    }
+
+    #[tokio::test]
+    async fn mock_invalid_vqd_retry_fails() {
+        let body = "{\"action\":\"error\",\"status\":400,\"type\":\"ERR_INVALID_VQD\"}";
+        let retry_body = "{\"action\":\"error\",\"status\":418,\"type\":\"ERR_INVALID_VQD\"}";
+        let (base_url, heads, _chats) = spawn_scripted_server(vec![
+            http_response("400 Bad Request", "application/json", body),
+            http_response("418 I'm a teapot", "application/json", retry_body),
+        ])
+        .await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .build()
+            .expect("failed to build client");
+        let mut request = ChatRequest::new(Model::Gpt4OMini);
+        request.set_vqd("stale-vqd".into());
+        request.push_user("Hello!");
+
+        let error = client.chat(&request).await.unwrap_err();
+        match error {
+            Error::Http {
+                status,
+                body: error_body,
+            } => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(error_body, body);
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+
+        // The new vqd is only tried once.
+        assert_eq!(heads.lock().expect("poisoned").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn mock_server_error_event() {
+        let body = format!(
+            "{}data: {{\"action\":\"error\",\"status\":429,\"type\":\"ERR_CONVERSATION_LIMIT\",\"message\":\"Too many messages\"}}\n\n",
+            message_event("Hello")
+        );
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let mut stream = client.chat(&request).await.expect("failed to chat");
+        let message = stream
+            .next()
+            .await
+            .expect("missing message")
+            .expect("failed to read message");
+        assert_eq!(message.message.as_deref(), Some("Hello"));
+        let error = stream
+            .next()
+            .await
+            .expect("missing error")
+            .expect_err("expected an error");
+        match error {
+            Error::ServerError { message, ty } => {
+                assert_eq!(message, "Too many messages");
+                assert_eq!(ty.as_deref(), Some("ERR_CONVERSATION_LIMIT"));
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+
+        // Without a message, the error type is used.
+        let body = "data: {\"action\":\"error\",\"status\":400,\"type\":\"ERR_BAD_REQUEST\"}\n\n";
+        let client = mock_client(sse_response(body)).await;
+        let mut stream = client.chat(&request).await.expect("failed to chat");
+        let error = stream
+            .next()
+            .await
+            .expect("missing error")
+            .expect_err("expected an error");
+        match error {
+            Error::ServerError { message, ty } => {
+                assert_eq!(message, "ERR_BAD_REQUEST");
+                assert_eq!(ty.as_deref(), Some("ERR_BAD_REQUEST"));
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+    }
+
+    #[test]
+    fn openai_request() {
+        use crate::compat::openai::ChatCompletionRequest;
+
+        let request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [
+                { "role": "developer", "content": "Be brief." },
+                { "role": "system", "content": "Be nice." },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "What is this?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,aGVsbG8=" } },
+                    { "type": "input_audio", "input_audio": { "data": "", "format": "wav" } },
+                    { "type": "text", "text": "Be specific." },
+                ] },
+                { "role": "assistant", "content": null, "tool_calls": [] },
+                { "role": "tool", "content": "42" },
+            ],
+            "stream": true,
+        }))
+        .expect("failed to parse request");
+        let request = ChatRequest::from(request);
+
+        assert_eq!(request.model, Model::Gpt4O);
+        let roles: Vec<_> = request
+            .messages
+            .iter()
+            .map(|message| message.role.clone())
+            .collect();
+        assert_eq!(
+            roles,
+            [
+                Role::System,
+                Role::System,
+                Role::User,
+                Role::Assistant,
+                Role::Other("tool".into())
+            ]
+        );
+        assert_eq!(request.messages[2].content, "What is this?\nBe specific.");
+        assert_eq!(
+            request.messages[2].attachments,
+            [Attachment::new(b"hello", "image/png")]
+        );
+        assert_eq!(request.messages[3].content, "");
+        assert_eq!(request.messages[4].content, "42");
+    }
+
+    #[tokio::test]
+    async fn mock_openai_sse_stream() {
+        use crate::compat::openai::ChatCompletionChunk;
+        use crate::compat::openai::OpenAiSseStream;
+
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let stream = client.chat(&request).await.expect("failed to chat");
+        let events: Vec<String> = OpenAiSseStream::new(stream)
+            .collect::<Result<_, _>>()
+            .await
+            .expect("failed to read stream");
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[3], "data: [DONE]\n\n");
+
+        let chunks: Vec<ChatCompletionChunk> = events[..3]
+            .iter()
+            .map(|event| {
+                let data = event
+                    .strip_prefix("data: ")
+                    .and_then(|event| event.strip_suffix("\n\n"))
+                    .expect("invalid event");
+                serde_json::from_str(data).expect("invalid chunk")
+            })
+            .collect();
+        for chunk in chunks.iter() {
+            assert_eq!(chunk.id, "mock-id");
+            assert_eq!(chunk.object, "chat.completion.chunk");
+            assert_eq!(chunk.model, "gpt-4o-mini");
+            assert_eq!(chunk.choices.len(), 1);
+        }
+        assert_eq!(
+            chunks[0].choices[0].delta.role.as_deref(),
+            Some("assistant")
+        );
+        assert_eq!(chunks[0].choices[0].delta.content.as_deref(), Some("Hello"));
+        assert_eq!(chunks[0].choices[0].finish_reason, None);
+        assert_eq!(
+            chunks[1].choices[0].delta.content.as_deref(),
+            Some(" world!")
+        );
+
+        let stop = &chunks[2].choices[0];
+        assert_eq!(stop.delta.role, None);
+        assert_eq!(stop.delta.content, None);
+        assert_eq!(stop.finish_reason.as_deref(), Some("stop"));
+    }
+
+    #[tokio::test]
+    async fn mock_vqd_hash_solver() {
+        let status_response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nx-vqd-4: mock-vqd\r\nx-vqd-hash-1: challenge-1\r\nConnection: close\r\n\r\n";
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let chat_response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: {}\r\nx-vqd-4: mock-vqd-2\r\nx-vqd-hash-1: challenge-2\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        let (base_url, heads, _chats) =
+            spawn_scripted_server_with_status(status_response.into(), vec![chat_response]).await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .vqd_hash_solver(|challenge| Some(format!("solved-{challenge}")))
+            .build()
+            .expect("failed to build client");
+
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        assert_eq!(request.vqd_hash(), Some("challenge-1"));
+        request.push_user("Hello!");
+
+        let mut stream = client.chat(&request).await.expect("failed to chat");
+        while let Some(message) = stream.next().await {
+            message.expect("failed to read message");
+        }
+        assert_eq!(stream.vqd_hash(), Some("challenge-2"));
+
+        // The latest challenge is solved for the next request.
+        request.set_vqd(stream.vqd().expect("missing vqd").into());
+        request.set_vqd_hash(stream.vqd_hash().expect("missing vqd hash").into());
+        request.push_assistant("Hello");
+        request.push_user("Hello again!");
+        client.chat_once(&request).await.expect("failed to chat");
+
+        let heads = heads.lock().expect("poisoned");
+        assert_eq!(heads.len(), 3);
+        assert!(!heads[0].contains("x-vqd-hash-1"), "{}", heads[0]);
+        assert!(
+            heads[1].contains("x-vqd-hash-1: solved-challenge-1"),
+            "{}",
+            heads[1]
+        );
+        assert!(
+            heads[2].contains("x-vqd-hash-1: solved-challenge-2"),
+            "{}",
+            heads[2]
+        );
+        assert!(heads[2].contains("x-vqd-4: mock-vqd-2"), "{}", heads[2]);
+    }
+
+    #[tokio::test]
+    async fn save_and_load_request() {
+        let mut request = ChatRequest::new(Model::Claude3Haiku);
+        request.set_system_prompt("Be brief.".into());
+        request.push_user("Hello!");
+        request.push_assistant("Hi!");
+        request.messages.push(ChatMessage::user_with_image(
+            "What is this?",
+            b"hello",
+            "image/png",
+        ));
+        request
+            .set_temperature(Some(0.5))
+            .set_top_p(Some(0.9))
+            .set_max_tokens(Some(100));
+        request.set_vqd("mock-vqd".into());
+        request.set_vqd_hash("mock-vqd-hash".into());
+
+        let mut saved = Vec::new();
+        request
+            .save_to_writer(&mut saved)
+            .expect("failed to save request");
+        let mut loaded =
+            ChatRequest::load_from_reader(saved.as_slice()).expect("failed to load request");
+
+        // Everything but the vqd survives the round-trip.
+        assert_eq!(loaded.vqd(), None);
+        assert_eq!(loaded.vqd_hash(), None);
+        assert_ne!(loaded, request);
+        loaded.set_vqd("mock-vqd".into());
+        loaded.set_vqd_hash("mock-vqd-hash".into());
+        assert_eq!(loaded, request);
+
+        let client = mock_client(sse_response("data: [DONE]\n\n")).await;
+        let resumed = client
+            .resume_chat(loaded.messages.clone())
+            .await
+            .expect("failed to resume chat");
+        assert_eq!(resumed.messages, request.messages);
+        assert_eq!(resumed.vqd(), Some("mock-vqd"));
+    }
+
+    #[tokio::test]
+    async fn mock_fetch_vqd() {
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let client = mock_client(sse_response(&body)).await;
+        let vqd = client.fetch_vqd().await.expect("failed to fetch vqd");
+        assert_eq!(vqd, "mock-vqd");
+
+        let mut request = ChatRequest::from_messages(vec![ChatMessage::user("Hello!")]);
+        request.set_vqd(vqd);
+        let message = client.chat_once(&request).await.expect("failed to chat");
+        assert_eq!(message.content, "Hello");
+    }
+
+    #[tokio::test]
+    async fn mock_observer() {
+        #[derive(Debug, Default)]
+        struct CountingObserver {
+            requests: AtomicUsize,
+            tokens: AtomicUsize,
+            errors: AtomicUsize,
+        }
+
+        impl Observer for Arc<CountingObserver> {
+            fn on_request(&self, _request: &reqwest::Request) {
+                self.requests.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_token(&self, _content: &str) {
+                self.tokens.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_error(&self, _error: &Error) {
+                self.errors.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world")
+        );
+        let base_url = spawn_mock_server(sse_response(&body)).await;
+        let observer = Arc::new(CountingObserver::default());
+        let client = Client::builder()
+            .base_url(&base_url)
+            .observer(observer.clone())
+            .build()
+            .expect("failed to build client");
+
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+        let message = client.chat_once(&request).await.expect("failed to chat");
+        assert_eq!(message.content, "Hello world");
+        assert_eq!(observer.requests.load(Ordering::SeqCst), 2);
+        assert_eq!(observer.tokens.load(Ordering::SeqCst), 2);
+        assert_eq!(observer.errors.load(Ordering::SeqCst), 0);
+
+        let request = ChatRequest::from_messages(vec![ChatMessage::user("Hello!")]);
+        let error = client.chat(&request).await.unwrap_err();
+        assert!(matches!(error, Error::MissingVqd), "{error:?}");
+        assert_eq!(observer.errors.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn mock_health_check() {
+        let client = mock_client(sse_response("data: [DONE]\n\n")).await;
+        client.health_check().await.expect("health check failed");
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = listener.local_addr().expect("missing local addr");
+        drop(listener);
+        let client = Client::builder()
+            .base_url(&format!("http://{addr}"))
+            .build()
+            .expect("failed to build client");
+        let error = client.health_check().await.unwrap_err();
+        assert!(error.is_unavailable(), "{error:?}");
+        assert!(!error.is_blocked(), "{error:?}");
+
+        let error = Error::Http {
+            status: StatusCode::FORBIDDEN,
+            body: String::new(),
+        };
+        assert!(error.is_blocked());
+        assert!(!error.is_unavailable());
+    }
+
+    #[tokio::test]
+    async fn mock_rate_limited() {
+        let response = "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nRetry-After: 30\r\nx-ratelimit-reset: 4102444800\r\nConnection: close\r\n\r\n";
+        let client = mock_client(response.into()).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let before = SystemTime::now();
+        let error = client.chat(&request).await.unwrap_err();
+        match error {
+            Error::RateLimited {
+                retry_after,
+                reset_at,
+                headers,
+            } => {
+                assert_eq!(retry_after, Some(Duration::from_secs(30)));
+                let reset_at = reset_at.expect("missing reset time");
+                assert!(reset_at >= before + Duration::from_secs(30));
+                assert!(reset_at <= SystemTime::now() + Duration::from_secs(30));
+                assert_eq!(headers["x-ratelimit-reset"], "4102444800");
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_rate_limited_http_date() {
+        let retry_at = SystemTime::now() + Duration::from_secs(120);
+        let response = format!(
+            "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nRetry-After: {}\r\nConnection: close\r\n\r\n",
+            httpdate::fmt_http_date(retry_at)
+        );
+        let client = mock_client(response).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let error = client.chat(&request).await.unwrap_err();
+        match error {
+            Error::RateLimited {
+                retry_after,
+                reset_at,
+                ..
+            } => {
+                // Http dates only have second precision.
+                let retry_after = retry_after.expect("missing retry after");
+                assert!(retry_after > Duration::from_secs(100), "{retry_after:?}");
+                assert!(retry_after <= Duration::from_secs(120), "{retry_after:?}");
+                assert!(reset_at.is_some());
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+
+        // A date in the past means no wait.
+        let response = "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nRetry-After: Wed, 21 Oct 2015 07:28:00 GMT\r\nConnection: close\r\n\r\n";
+        let client = mock_client(response.into()).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let error = client.chat(&request).await.unwrap_err();
+        assert!(
+            matches!(
+                error,
+                Error::RateLimited {
+                    retry_after: Some(Duration::ZERO),
+                    ..
+                }
+            ),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_rate_limit_retry() {
+        let rate_limited = "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nRetry-After: 0\r\nConnection: close\r\n\r\n";
+        let (base_url, heads, _chats) = spawn_scripted_server(vec![
+            rate_limited.into(),
+            sse_response(&format!("{}data: [DONE]\n\n", message_event("Hello"))),
+        ])
+        .await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .max_retries(1)
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let message = client.chat_once(&request).await.expect("failed to chat");
+        assert_eq!(message.content, "Hello");
+        // The handshake, the rate limited request, and a single retry.
+        assert_eq!(heads.lock().expect("poisoned").len(), 3);
+
+        // Once retries run out, the rate limit is returned.
+        let (base_url, heads, _chats) = spawn_scripted_server(vec![rate_limited.into()]).await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .max_retries(2)
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let error = client.chat(&request).await.unwrap_err();
+        assert!(
+            matches!(
+                error,
+                Error::RateLimited {
+                    retry_after: Some(Duration::ZERO),
+                    ..
+                }
+            ),
+            "{error:?}"
+        );
+        assert_eq!(heads.lock().expect("poisoned").len(), 4);
+    }
+
+    #[tokio::test]
+    async fn mock_sleep_fn() {
+        let rate_limited = "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nRetry-After: 7\r\nConnection: close\r\n\r\n";
+        let (base_url, _heads, _chats) = spawn_scripted_server(vec![
+            rate_limited.into(),
+            sse_response(&format!("{}data: [DONE]\n\n", message_event("Hello"))),
+        ])
+        .await;
+        let waits = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let client = Client::builder()
+            .base_url(&base_url)
+            .max_retries(1)
+            .sleep_fn({
+                let waits = waits.clone();
+                move |duration| {
+                    waits.lock().expect("poisoned").push(duration);
+                    Box::pin(std::future::ready(()))
+                }
+            })
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let message = client.chat_once(&request).await.expect("failed to chat");
+        assert_eq!(message.content, "Hello");
+        assert_eq!(*waits.lock().expect("poisoned"), [Duration::from_secs(7)]);
+    }
+
+    #[tokio::test]
+    async fn mock_blank_vqd() {
+        let status_response =
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nx-vqd-4: \r\nConnection: close\r\n\r\n";
+        let (base_url, _heads, _chats) =
+            spawn_scripted_server_with_status(status_response.into(), Vec::new()).await;
+
+        let client = Client::builder()
+            .base_url(&base_url)
+            .build()
+            .expect("failed to build client");
+        let error = client.fetch_vqd().await.unwrap_err();
+        assert!(
+            matches!(error, Error::InvalidVqd(ref vqd) if vqd.is_empty()),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_chat_with_messages() {
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let client = mock_client(sse_response(&body)).await;
+        let message = client
+            .chat_with_messages("gpt-4o-mini", &[ChatMessage::user("Hello!")])
+            .await
+            .expect("failed to chat");
+        assert_eq!(message.content, "Hello");
+    }
+
+    #[tokio::test]
+    async fn mock_sse_buffer_capacity() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let base_url = spawn_mock_server(sse_response(&body)).await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .sse_buffer_capacity(1)
+            .build()
+            .expect("failed to build client");
+        let message = client
+            .chat_with_messages("gpt-4o-mini", &[ChatMessage::user("Hello!")])
+            .await
+            .expect("failed to chat");
+        assert_eq!(message.content, "Hello world!");
+    }
+
+    #[tokio::test]
+    async fn mock_chat_stream_text() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let stream = client
+            .chat_stream_text(&request)
+            .await
+            .expect("failed to chat");
+        let parts: Vec<String> = stream
+            .collect::<Result<_, _>>()
+            .await
+            .expect("failed to collect parts");
+        assert_eq!(parts, ["Hello", " world!"]);
+    }
+
+    #[test]
+    fn dry_run_chat() {
+        let client = Client::builder()
+            .base_url("http://127.0.0.1")
+            .request_middleware(|request| request.query(&[("debug", "1")]))
+            .build()
+            .expect("failed to build client");
+        let mut request = ChatRequest::new(Model::Gpt4OMini);
+        request.push_user("Hello!");
+        assert!(matches!(
+            client.dry_run_chat(&request),
+            Err(Error::MissingVqd)
+        ));
+
+        request.set_vqd("mock-vqd".into());
+        let wire_json = request.to_wire_json().expect("failed to serialize");
+        assert_eq!(
+            wire_json,
+            r#"{"messages":[{"role":"user","content":"Hello!"}],"model":"gpt-4o-mini"}"#
+        );
+
+        let http_request = client
+            .dry_run_chat(&request)
+            .expect("failed to build request");
+        assert_eq!(
+            http_request.url().as_str(),
+            "http://127.0.0.1/duckchat/v1/chat?debug=1"
+        );
+        assert_eq!(http_request.headers()["x-vqd-4"], "mock-vqd");
+        let body = http_request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .expect("missing body");
+        assert_eq!(body, wire_json.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn mock_headers() {
+        use reqwest::header::HeaderName;
+        use reqwest::header::HeaderValue;
+
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let (base_url, heads, _chats) = spawn_scripted_server(vec![sse_response(&body)]).await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .default_header(
+                HeaderName::from_static("x-test"),
+                HeaderValue::from_static("default"),
+            )
+            .default_header(
+                HeaderName::from_static("x-vqd-4"),
+                HeaderValue::from_static("default-vqd"),
+            )
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let http_request = client
+            .dry_run_chat(&request)
+            .expect("failed to build request");
+        assert_eq!(http_request.headers()["x-test"], "default");
+        let vqds: Vec<_> = http_request.headers().get_all("x-vqd-4").iter().collect();
+        assert_eq!(vqds, ["mock-vqd"]);
+
+        let mut extra_headers = HeaderMap::new();
+        extra_headers.insert("x-extra", HeaderValue::from_static("extra"));
+        extra_headers.insert("x-test", HeaderValue::from_static("override"));
+        extra_headers.insert("x-vqd-4", HeaderValue::from_static("extra-vqd"));
+        let mut stream = client
+            .chat_with_headers(&request, &extra_headers)
+            .await
+            .expect("failed to chat");
+        while let Some(message) = stream.next().await {
+            message.expect("failed to read message");
+        }
+
+        let heads = heads.lock().expect("poisoned");
+        assert!(heads[0].contains("x-test: default"), "{}", heads[0]);
+        let chat_head = &heads[1];
+        assert!(chat_head.contains("x-extra: extra"), "{chat_head}");
+        assert!(chat_head.contains("x-test: override"), "{chat_head}");
+        assert!(!chat_head.contains("x-test: default"), "{chat_head}");
+        assert!(chat_head.contains("x-vqd-4: mock-vqd"), "{chat_head}");
+        assert!(!chat_head.contains("default-vqd"), "{chat_head}");
+        assert!(!chat_head.contains("extra-vqd"), "{chat_head}");
+    }
+
+    #[tokio::test]
+    async fn mock_handshake_timeout() {
+        let base_url = spawn_stalled_server_with_status(None).await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .handshake_timeout(Duration::from_millis(100))
+            .build()
+            .expect("failed to build client");
+        let error = tokio::time::timeout(Duration::from_secs(5), client.init_chat())
+            .await
+            .expect("handshake did not time out")
+            .unwrap_err();
+        assert!(
+            matches!(&error, Error::Reqwest(error) if error.is_timeout()),
+            "{error:?}"
+        );
+        assert!(error.is_unavailable());
+    }
+
+    #[tokio::test]
+    async fn mock_retry_empty_responses() {
+        let (base_url, _heads, chats) = spawn_scripted_server(vec![
+            sse_response("data: [DONE]\n\n"),
+            sse_response(&format!("{}data: [DONE]\n\n", message_event("Hello"))),
+        ])
+        .await;
+
+        let client = Client::builder()
+            .base_url(&base_url)
+            .retry_empty_responses(2)
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+        let message = client
+            .chat_and_append(&mut request)
+            .await
+            .expect("failed to chat");
+        assert_eq!(message.content, "Hello");
+        assert_eq!(chats.load(Ordering::SeqCst), 2);
+        assert_eq!(request.messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn mock_chat_all() {
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let client = mock_client(sse_response(&body)).await;
+        let models = [
+            "gpt-4o-mini".to_string(),
+            "gpt-4".to_string(),
+            "claude-3-haiku-20240307".to_string(),
+        ];
+        let results = client
+            .chat_all(&models, &[ChatMessage::user("Hello!")])
+            .await;
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0].as_ref().expect("failed to chat").content,
+            "Hello"
+        );
+        assert!(matches!(results[1], Err(Error::RetiredModel(_))));
+        assert_eq!(
+            results[2].as_ref().expect("failed to chat").content,
+            "Hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_chat_with_callback() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+
+        let mut parts = Vec::new();
+        let message = client
+            .chat_with_callback(&request, |content| parts.push(content.to_string()))
+            .await
+            .expect("failed to chat");
+        assert_eq!(parts, ["Hello", " world!"]);
+        assert_eq!(message.content, "Hello world!");
+    }
+
+    #[tokio::test]
+    async fn mock_model_mismatch() {
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client
+            .init_chat_with_model(Model::Claude3Haiku)
+            .await
+            .expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+
+        let mut stream = client
+            .chat(&request)
+            .await
+            .expect("failed to send chat request");
+        stream
+            .collect_into_chat_message()
+            .await
+            .expect("failed to collect message");
+        assert_eq!(stream.served_model(), Some("gpt-4o-mini"));
+        assert!(stream.is_model_mismatch());
+
+        let mut stream = client
+            .chat(&request)
+            .await
+            .expect("failed to send chat request")
+            .with_strict_model();
+        let error = stream.next().await.expect("missing error").unwrap_err();
+        assert!(matches!(error, Error::ModelMismatch { .. }), "{error:?}");
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn mock_write_all_to() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+
+        let mut stream = client
+            .chat(&request)
+            .await
+            .expect("failed to send chat request");
+        let mut output = Vec::new();
+        let message = stream
+            .write_all_to(&mut output)
+            .await
+            .expect("failed to write message");
+        assert_eq!(output, b"Hello world!");
+        assert_eq!(message.content, "Hello world!");
+    }
+
+    #[tokio::test]
+    async fn mock_into_channel() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+
+        let stream = client
+            .chat(&request)
+            .await
+            .expect("failed to send chat request");
+        let (finish_tx, finish_rx) = tokio::sync::oneshot::channel();
+        let mut rx = stream.into_channel(1, move |message| {
+            let _ = finish_tx.send(message).is_ok();
+        });
+
+        let mut parts = Vec::new();
+        while let Some(message) = rx.recv().await {
+            let message = message.expect("failed to stream message");
+            assert_eq!(message.model, "gpt-4o-mini");
+            parts.push(message.message.expect("missing content"));
+        }
+        assert_eq!(parts, ["Hello", " world!"]);
+
+        let message = finish_rx
+            .await
+            .expect("on_finish was not called")
+            .expect("missing message");
+        assert_eq!(message.content, "Hello world!");
+    }
+
+    #[tokio::test]
+    async fn mock_regenerate() {
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+
+        let error = client.regenerate(&mut request).await.unwrap_err();
+        assert!(matches!(error, Error::NothingToRegenerate), "{error:?}");
+
+        request.messages.push(ChatMessage::assistant("Goodbye"));
+        let message = client
+            .regenerate(&mut request)
+            .await
+            .expect("failed to regenerate");
+        assert_eq!(message.content, "Hello");
+        assert_eq!(request.messages.len(), 2);
+        assert_eq!(request.messages[1].content, "Hello");
+    }
+
+    #[tokio::test]
+    async fn mock_into_async_read() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello\n"),
+            message_event("world!")
+        );
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+
+        let stream = client
+            .chat(&request)
+            .await
+            .expect("failed to send chat request");
+        let mut content = String::new();
+        stream
+            .into_async_read()
+            .read_to_string(&mut content)
+            .await
+            .expect("failed to read content");
+        assert_eq!(content, "Hello\nworld!");
+    }
+
+    #[tokio::test]
+    async fn into_jsonl_stream() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let stream = ChatResponseStream::from_reader(std::io::Cursor::new(body), Model::Gpt4OMini);
+        let lines: Vec<String> = stream
+            .into_jsonl_stream()
+            .collect::<Result<_, _>>()
+            .await
+            .expect("failed to collect lines");
+        assert_eq!(lines.len(), 2);
+        for (line, content) in lines.iter().zip(["Hello", " world!"]) {
+            let line = line.strip_suffix('\n').expect("missing newline");
+            assert!(!line.contains('\n'));
+            let message: serde_json::Value = serde_json::from_str(line).expect("invalid json");
+            assert_eq!(message["message"], content);
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_html_response() {
+        let body = "<html><body>Are you a robot?</body></html>";
+        let client = mock_client(http_response("200 OK", "text/html; charset=utf-8", body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let error = client.chat(&request).await.unwrap_err();
+        match error {
+            Error::UnexpectedContentType {
+                content_type,
+                body_snippet,
+            } => {
+                assert_eq!(content_type, "text/html; charset=utf-8");
+                assert_eq!(body_snippet, body);
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn require_done() {
+        let body = message_event("Hello");
+        let mut stream =
+            ChatResponseStream::from_reader(std::io::Cursor::new(body.clone()), Model::Gpt4OMini);
+        let message = stream
+            .collect_into_chat_message()
+            .await
+            .expect("failed to collect message");
+        assert_eq!(message.content, "Hello");
+        assert!(!stream.is_complete());
+
+        let mut stream =
+            ChatResponseStream::from_reader(std::io::Cursor::new(body), Model::Gpt4OMini)
+                .with_require_done();
+        let error = stream.collect_into_chat_message().await.unwrap_err();
+        match error {
+            Error::PartialResponse { content, source } => {
+                assert_eq!(content, "Hello");
+                assert!(matches!(*source, Error::IncompleteStream), "{source:?}");
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn non_answer_actions() {
+        let keepalive = serde_json::json!({
+            "role": "assistant",
+            "message": "ignored",
+            "created": 1,
+            "id": "mock-id",
+            "action": "keepalive",
+            "model": "gpt-4o-mini",
+        });
+        let body = format!(
+            "{}data: {keepalive}\n\n{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let mut stream =
+            ChatResponseStream::from_reader(std::io::Cursor::new(body), Model::Gpt4OMini);
+
+        let first = stream
+            .next()
+            .await
+            .expect("missing message")
+            .expect("failed to read message");
+        assert_eq!(first.action, model::Action::Answer);
+        let second = stream
+            .next()
+            .await
+            .expect("missing message")
+            .expect("failed to read message");
+        assert_eq!(second.action, model::Action::Other("keepalive".into()));
+        assert_eq!(second.message, None);
+
+        let message = stream
+            .collect_into_chat_message()
+            .await
+            .expect("failed to collect message");
+        assert_eq!(message.content, " world!");
+    }
+
+    #[tokio::test]
+    async fn map_content() {
+        let body = format!(
+            "{}{}{}{}data: [DONE]\n\n",
+            message_event(" "),
+            message_event("\n Hello"),
+            message_event(" "),
+            message_event("world!")
+        );
+        let mut stream =
+            ChatResponseStream::from_reader(std::io::Cursor::new(body.clone()), Model::Gpt4OMini)
+                .trim_leading();
+        let message = stream
+            .collect_into_chat_message()
+            .await
+            .expect("failed to collect message");
+        assert_eq!(message.content, "Hello world!");
+
+        let mut stream =
+            ChatResponseStream::from_reader(std::io::Cursor::new(body), Model::Gpt4OMini)
+                .trim_leading()
+                .map_content(|content| content.to_uppercase());
+        let message = stream
+            .collect_into_chat_message()
+            .await
+            .expect("failed to collect message");
+        assert_eq!(message.content, "HELLO WORLD!");
+    }
+
+    #[tokio::test]
+    async fn into_progress_channel() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let stream = ChatResponseStream::from_reader(std::io::Cursor::new(body), Model::Gpt4OMini);
+        let mut rx = stream.into_progress_channel(1);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 4);
+        assert!(matches!(&events[0], ProgressEvent::Started { model } if model == "gpt-4o-mini"));
+        assert!(matches!(
+            &events[1],
+            ProgressEvent::Delta { content, tokens_received: 1, bytes_received: 5 } if content == "Hello"
+        ));
+        assert!(matches!(
+            &events[2],
+            ProgressEvent::Delta {
+                tokens_received: 2,
+                bytes_received: 12,
+                ..
+            }
+        ));
+        match &events[3] {
+            ProgressEvent::Finished { message } => assert_eq!(message.content, "Hello world!"),
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn sse_stream_chunks() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world")
+        );
+        // Split the body into small chunks, so events span several of them.
+        let chunks: Vec<Result<bytes::Bytes, std::io::Error>> = body
+            .as_bytes()
+            .chunks(7)
+            .map(|chunk| Ok(bytes::Bytes::copy_from_slice(chunk)))
+            .collect();
+        let stream = sse::SseStream::new(tokio_stream::iter(chunks), Some(16));
+        let mut stream = ChatResponseStream::new(Box::pin(stream), Model::Gpt4OMini, None, None);
+        let message = stream
+            .collect_into_chat_message()
+            .await
+            .expect("failed to collect message");
+        assert_eq!(message.content, "Hello world");
+        assert!(stream.is_complete());
+    }
+
+    #[tokio::test]
+    async fn sse_decoder() {
+        let decode = |chunks: &[&[u8]]| {
+            let chunks: Vec<Result<bytes::Bytes, std::io::Error>> = chunks
+                .iter()
+                .map(|chunk| Ok(bytes::Bytes::copy_from_slice(chunk)))
+                .collect();
+            sse::SseStream::new(tokio_stream::iter(chunks), None)
+                .map(|event| event.map(|event| event.data))
+                .collect::<Vec<_>>()
+        };
+
+        // Line endings may be split across chunks.
+        let events = decode(&[
+            b"\xEF\xBB\xBFdata: a\r",
+            b"\ndata:b\r\r: comment\nevent: ping\n\n",
+            b"data\n\ndata: trailing",
+        ])
+        .await;
+        let events: Vec<_> = events
+            .into_iter()
+            .map(|event| event.expect("invalid event"))
+            .collect();
+        assert_eq!(
+            events,
+            [Some("a\nb".to_string()), None, Some(String::new())]
+        );
+
+        let events = decode(&[b"data: \xFF\n\n"]).await;
+        assert_eq!(events.len(), 1);
+        let error = events[0].as_ref().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn parse_escaped_events() {
+        let body = format!(
+            "{}data: {{\"role\":\"assistant\",\"message\":\" \\\"world\\\"\\n\",\"created\":2,\"id\":\"mock-id\",\"action\":\"success\",\"model\":\"gpt-4o-mini\"}}\n\ndata: {{\"action\":\"success\"}}\n\n",
+            message_event("Hello"),
+        );
+        let mut stream =
+            ChatResponseStream::from_reader(std::io::Cursor::new(body), Model::Gpt4OMini);
+        let error = stream
+            .collect_into_chat_message()
+            .await
+            .expect_err("expected a missing field");
+        match error {
+            Error::PartialResponse { content, source } => {
+                // Escaped strings cannot be borrowed, but are still parsed.
+                assert_eq!(content, "Hello \"world\"\n");
+                assert!(
+                    matches!(*source, Error::InvalidSseEventData(_)),
+                    "{source:?}"
+                );
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn deadline() {
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        writer
+            .write_all(message_event("Hello").as_bytes())
+            .await
+            .expect("failed to write");
+
+        // The writer is kept open, so the stream only ends because of the deadline.
+        let mut stream = ChatResponseStream::from_reader(reader, Model::Gpt4OMini)
+            .with_idle_timeout(Duration::from_secs(60))
+            .with_deadline(Duration::from_millis(50));
+        let message = stream
+            .next()
+            .await
+            .expect("stream ended")
+            .expect("failed to read message");
+        assert_eq!(message.message.as_deref(), Some("Hello"));
+
+        let error = stream.next().await.expect("stream ended").unwrap_err();
+        assert!(matches!(error, Error::DeadlineExceeded), "{error:?}");
+        assert!(stream.next().await.is_none());
+        drop(writer);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let (mut writer, reader) = tokio::io::duplex(1024);
+
+        // The timer only starts once the stream is polled.
+        let mut stream = ChatResponseStream::from_reader(reader, Model::Gpt4OMini)
+            .with_idle_timeout(Duration::from_millis(200));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let write = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            writer
+                .write_all(message_event("Hello").as_bytes())
+                .await
+                .expect("failed to write");
+            writer
+        });
+        let message = stream
+            .next()
+            .await
+            .expect("stream ended")
+            .expect("failed to read message");
+        assert_eq!(message.message.as_deref(), Some("Hello"));
+
+        // The writer is kept open, so the stream only ends because of the idle timeout.
+        let writer = write.await.expect("failed to join");
+        let error = stream.next().await.expect("stream ended").unwrap_err();
+        assert!(matches!(error, Error::IdleTimeout), "{error:?}");
+        assert!(stream.next().await.is_none());
+        drop(writer);
+    }
+
+    #[test]
+    fn idle_timeout_without_runtime() {
+        let reader = std::io::Cursor::new(Vec::new());
+        let _stream = ChatResponseStream::from_reader(reader, Model::Gpt4OMini)
+            .with_idle_timeout(Duration::from_secs(1))
+            .with_deadline(Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn stop_at() {
+        let body = format!(
+            "{}{}{}data: [DONE]\n\n",
+            message_event("Hello <"),
+            message_event("/st"),
+            message_event("op> world!")
+        );
+        let stop_at = |body: String, sequences: &[&str]| {
+            ChatResponseStream::from_reader(std::io::Cursor::new(body), Model::Gpt4OMini).stop_at(
+                sequences
+                    .iter()
+                    .map(|sequence| sequence.to_string())
+                    .collect(),
+            )
+        };
+
+        let mut stream = stop_at(body.clone(), &["</stop>", "never"]);
+        let message = stream
+            .collect_into_chat_message()
+            .await
+            .expect("failed to collect message");
+        assert_eq!(message.content, "Hello ");
+        assert!(stream.is_complete());
+
+        let mut stream = stop_at(body.clone(), &["</stopped>"]);
+        let message = stream
+            .collect_into_chat_message()
+            .await
+            .expect("failed to collect message");
+        assert_eq!(message.content, "Hello </stop> world!");
+
+        let mut stream = stop_at(body, &["world!"]);
+        let message = stream
+            .collect_into_chat_message()
+            .await
+            .expect("failed to collect message");
+        assert_eq!(message.content, "Hello </stop> ");
+    }
+
+    #[tokio::test]
+    async fn mock_content_filter() {
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" secret")
+        );
+        let base_url = spawn_mock_server(sse_response(&body)).await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .content_filter(|content| match content {
+                "Bad!" => FilterAction::Reject,
+                " secret" => FilterAction::Redact(" [redacted]".into()),
+                _ => FilterAction::Allow,
+            })
+            .filter_responses(true)
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+
+        request.messages.push(ChatMessage::user("Bad!"));
+        let error = client.chat(&request).await.unwrap_err();
+        assert!(matches!(error, Error::Filtered), "{error:?}");
+
+        request.messages[0] = ChatMessage::user("Hello!");
+        let message = client.chat_once(&request).await.expect("failed to chat");
+        assert_eq!(message.content, "Hello [redacted]");
+    }
+
+    #[tokio::test]
+    async fn mock_stream_errors() {
+        let body = format!(
+            "{}data: {{not json}}\n\nevent: ping\n\ndata: [DONE]\n\n{}",
+            message_event("Hello"),
+            message_event("ignored"),
+        );
+        let client = mock_client(sse_response(&body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+
+        let mut stream = client
+            .chat(&request)
+            .await
+            .expect("failed to send chat request");
+
+        let message = stream
+            .next()
+            .await
+            .expect("missing message")
+            .expect("invalid message");
+        assert_eq!(message.message.as_deref(), Some("Hello"));
+
+        let error = stream.next().await.expect("missing error").unwrap_err();
+        assert!(matches!(error, Error::InvalidSseEventData(_)), "{error:?}");
+
+        let error = stream.next().await.expect("missing error").unwrap_err();
+        assert!(matches!(error, Error::SseEventMissingData), "{error:?}");
+
+        assert!(stream.next().await.is_none());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn mock_concurrent_chats() {
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let client = mock_client(sse_response(&body)).await;
+
+        let tasks: Vec<_> = (0..16)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let mut request = client.init_chat().await.expect("failed to init chat");
+                    request
+                        .messages
+                        .push(ChatMessage::user(format!("Hello {i}!")));
+                    client
+                        .chat_and_append(&mut request)
+                        .await
+                        .expect("failed to chat");
+                    request
+                })
+            })
+            .collect();
+
+        for (i, task) in tasks.into_iter().enumerate() {
+            let request = task.await.expect("task panicked");
+            assert_eq!(request.messages.len(), 2);
+            assert_eq!(request.messages[0].content, format!("Hello {i}!"));
+            assert_eq!(request.messages[1].content, "Hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_error() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let addr = listener.local_addr().expect("missing local addr");
+        drop(listener);
+
+        let client = Client::builder()
+            .base_url(&format!("http://{addr}"))
+            .build()
+            .expect("failed to build client");
+        let error = client.init_chat().await.unwrap_err();
+        assert!(matches!(error, Error::Connect(_)), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn mock_http_error() {
+        let body = "{\"action\":\"error\",\"status\":400,\"type\":\"ERR_BAD_REQUEST\"}";
+        let client = mock_client(http_response("400 Bad Request", "application/json", body)).await;
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+
+        let error = client.chat(&request).await.unwrap_err();
+        match error {
+            Error::Http {
+                status,
+                body: error_body,
+            } => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(error_body, body);
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn blocking_client() {
+        let server_runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build runtime");
+        let body = format!(
+            "{}{}data: [DONE]\n\n",
+            message_event("Hello"),
+            message_event(" world!")
+        );
+        let base_url = server_runtime.block_on(spawn_mock_server(sse_response(&body)));
+        std::thread::spawn(move || server_runtime.block_on(std::future::pending::<()>()));
+
+        let client = Client::builder()
+            .base_url(&base_url)
+            .build()
+            .expect("failed to build client");
+        let client = blocking::Client::from_async(client).expect("failed to build client");
+        let mut request = client.init_chat().expect("failed to init chat");
+        request.push_user("Hello!");
+
+        let message = client.chat_once(&request).expect("failed to chat");
+        assert_eq!(message.content, "Hello world!");
+
+        let stream = client.chat(&request).expect("failed to chat");
+        let content = stream
+            .map(|message| message.expect("failed to read message").message)
+            .collect::<Option<String>>();
+        assert_eq!(content.as_deref(), Some("Hello world!"));
+    }
+
+    #[tokio::test]
+    async fn custom_transport() {
+        /// A transport that counts the requests it sends.
+        #[derive(Debug)]
+        struct CountingTransport {
+            inner: crate::transport::ReqwestTransport,
+            status: Arc<AtomicUsize>,
+            chat: Arc<AtomicUsize>,
+        }
+
+        impl Transport for CountingTransport {
+            fn execute(&self, request: reqwest::Request) -> crate::transport::TransportFuture<'_> {
+                self.inner.execute(request)
+            }
+
+            fn status(&self, request: reqwest::Request) -> crate::transport::TransportFuture<'_> {
+                self.status.fetch_add(1, Ordering::SeqCst);
+                self.inner.status(request)
+            }
+
+            fn chat(&self, request: reqwest::Request) -> crate::transport::TransportFuture<'_> {
+                self.chat.fetch_add(1, Ordering::SeqCst);
+                self.inner.chat(request)
+            }
+        }
+
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let base_url = spawn_mock_server(sse_response(&body)).await;
+        let status = Arc::new(AtomicUsize::new(0));
+        let chat = Arc::new(AtomicUsize::new(0));
+        let client = Client::builder()
+            .base_url(&base_url)
+            .transport(CountingTransport {
+                inner: crate::transport::ReqwestTransport::new(reqwest::Client::new()),
+                status: status.clone(),
+                chat: chat.clone(),
+            })
+            .build()
+            .expect("failed to build client");
+
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+        let message = client.chat_once(&request).await.expect("failed to chat");
+        assert_eq!(message.content, "Hello");
+        assert_eq!(status.load(Ordering::SeqCst), 1);
+        assert_eq!(chat.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn mock_request_middleware() {
+        let body = format!("{}data: [DONE]\n\n", message_event("Hello"));
+        let base_url = spawn_mock_server(sse_response(&body)).await;
+        let count = Arc::new(AtomicUsize::new(0));
+        let client = Client::builder()
+            .base_url(&base_url)
+            .request_middleware({
+                let count = count.clone();
+                move |request| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    request.header("x-extra", "1")
+                }
+            })
+            .build()
+            .expect("failed to build client");
+
+        let message = client
+            .chat_with_messages("gpt-4o-mini", &[ChatMessage::user("Hello!")])
+            .await
+            .expect("failed to chat");
+        assert_eq!(message.content, "Hello");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "replay")]
+    #[tokio::test]
+    async fn record_and_replay() {
+        let dir = std::env::temp_dir().join(format!(
+            "duck-duck-go-ai-replay-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("time went backwards")
+                .as_nanos()
+        ));
+        let body = format!("{}data: [DONE]\n\n", message_event("Recorded"));
+
+        let base_url = spawn_mock_server(sse_response(&body)).await;
+        let client = Client::builder()
+            .base_url(&base_url)
+            .record_to_dir(&dir)
+            .build()
+            .expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        request.messages.push(ChatMessage::user("Hello!"));
+        let message = client
+            .chat_once(&request)
+            .await
+            .expect("failed to record chat");
+        assert_eq!(message.content, "Recorded");
+
+        let client = Client::replay_from_dir(&dir).expect("failed to build client");
+        let mut request = client.init_chat().await.expect("failed to init chat");
+        assert_eq!(request.vqd(), Some("mock-vqd"));
+        request.messages.push(ChatMessage::user("Hello!"));
+        let message = client
+            .chat_once(&request)
+            .await
+            .expect("failed to replay chat");
+        assert_eq!(message.content, "Recorded");
+
+        let error = client.init_chat().await.unwrap_err();
+        assert!(matches!(error, Error::Io(_)), "{error:?}");
+
+        std::fs::remove_dir_all(&dir).expect("failed to remove dir");
+    }
 }
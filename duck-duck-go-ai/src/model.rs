@@ -1,142 +1,1900 @@
+use crate::sse::SseEvent;
+#[cfg(feature = "tokio")]
+use crate::sse::SseStream;
 use crate::Error;
-use nd_tokio_sse_codec::SseCodecError;
-use nd_tokio_sse_codec::SseEvent;
+use crate::Observer;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+#[cfg(feature = "tokio")]
+use bytes::Bytes;
+use futures_util::future::select;
+use futures_util::stream::FusedStream;
+use std::borrow::Cow;
+use std::convert::Infallible;
+use std::future::Future;
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::task::ready;
 use std::task::Context;
 use std::task::Poll;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncBufRead;
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncRead;
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncWrite;
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncWriteExt;
+#[cfg(feature = "tokio")]
+use tokio::time::Instant;
+#[cfg(feature = "tokio")]
+use tokio::time::Sleep;
 use tokio_stream::Stream;
 use tokio_stream::StreamExt;
+#[cfg(feature = "tokio")]
+use tokio_util::io::ReaderStream;
+#[cfg(feature = "tokio")]
+use tokio_util::io::StreamReader;
+use tokio_util::sync::CancellationToken;
+
+/// The model used when none is specified.
+pub(crate) const DEFAULT_MODEL: Model = Model::Gpt4OMini;
+
+/// The number of content parts to reserve space for when collected message content is full.
+///
+/// Content parts are usually a few bytes each,
+/// so reserving for many at once, based on their size, avoids most reallocations.
+const RESERVED_CONTENT_PARTS: usize = 64;
+
+/// Models that were valid in the past, but seem to no longer work, along with their replacements.
+const RETIRED_MODELS: &[(&str, Model)] = &[
+    ("meta-llama/Llama-3-70b-chat-hf", Model::MetaLlama31_70B),
+    ("gpt-3.5-turbo-0125", Model::Gpt4OMini),
+    ("gpt-4", Model::Gpt4O),
+];
+
+/// A chat model.
+///
+/// These choices were valid in the past,
+/// but seem to no longer work:
+/// * "meta-llama/Llama-3-70b-chat-hf"
+/// * "gpt-3.5-turbo-0125"
+/// * "gpt-4"
+///
+/// See [`Model::is_retired`] and [`Model::replacement`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Model {
+    /// "gpt-4o-mini"
+    Gpt4OMini,
+
+    /// "gpt-4o"
+    Gpt4O,
+
+    /// "claude-3-haiku-20240307"
+    Claude3Haiku,
+
+    /// "claude-3-sonnet-20240229"
+    Claude3Sonnet,
+
+    /// "claude-3-5-sonnet-20240620"
+    Claude35Sonnet,
+
+    /// "meta-llama/Meta-Llama-3.1-70B-Instruct-Turbo"
+    MetaLlama31_70B,
+
+    /// "mistralai/Mixtral-8x7B-Instruct-v0.1"
+    Mixtral8x7B,
+
+    /// A model not known by this library.
+    Other(String),
+}
+
+impl Model {
+    /// The models known to work.
+    pub const KNOWN: &'static [Model] = &[
+        Self::Gpt4OMini,
+        Self::Gpt4O,
+        Self::Claude3Haiku,
+        Self::Claude3Sonnet,
+        Self::Claude35Sonnet,
+        Self::MetaLlama31_70B,
+        Self::Mixtral8x7B,
+    ];
+
+    /// Get the wire string for this model.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Gpt4OMini => "gpt-4o-mini",
+            Self::Gpt4O => "gpt-4o",
+            Self::Claude3Haiku => "claude-3-haiku-20240307",
+            Self::Claude3Sonnet => "claude-3-sonnet-20240229",
+            Self::Claude35Sonnet => "claude-3-5-sonnet-20240620",
+            Self::MetaLlama31_70B => "meta-llama/Meta-Llama-3.1-70B-Instruct-Turbo",
+            Self::Mixtral8x7B => "mistralai/Mixtral-8x7B-Instruct-v0.1",
+            Self::Other(model) => model.as_str(),
+        }
+    }
+
+    /// Check if this model is known to no longer work.
+    pub fn is_retired(&self) -> bool {
+        self.replacement().is_some()
+    }
+
+    /// Get the suggested replacement for this model, if it is retired.
+    pub fn replacement(&self) -> Option<Model> {
+        RETIRED_MODELS
+            .iter()
+            .find(|(retired, _)| *retired == self.as_str())
+            .map(|(_, replacement)| replacement.clone())
+    }
+
+    /// Fail with [`Error::RetiredModel`] if this model is retired.
+    pub(crate) fn check_not_retired(&self) -> Result<(), Error> {
+        if !self.is_retired() {
+            return Ok(());
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            model = %self,
+            replacement = ?self.replacement().map(|model| model.to_string()),
+            "model is retired"
+        );
+        Err(Error::RetiredModel(self.to_string()))
+    }
+
+    /// Parse a known model from a wire string.
+    fn from_known_str(model: &str) -> Option<Self> {
+        match model {
+            "gpt-4o-mini" => Some(Self::Gpt4OMini),
+            "gpt-4o" => Some(Self::Gpt4O),
+            "claude-3-haiku-20240307" => Some(Self::Claude3Haiku),
+            "claude-3-sonnet-20240229" => Some(Self::Claude3Sonnet),
+            "claude-3-5-sonnet-20240620" => Some(Self::Claude35Sonnet),
+            "meta-llama/Meta-Llama-3.1-70B-Instruct-Turbo" => Some(Self::MetaLlama31_70B),
+            "mistralai/Mixtral-8x7B-Instruct-v0.1" => Some(Self::Mixtral8x7B),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Model {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Model {
+    type Err = Infallible;
+
+    fn from_str(model: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from_known_str(model).unwrap_or_else(|| Self::Other(model.into())))
+    }
+}
+
+impl From<&str> for Model {
+    fn from(model: &str) -> Self {
+        Self::from_known_str(model).unwrap_or_else(|| Self::Other(model.into()))
+    }
+}
+
+impl From<String> for Model {
+    fn from(model: String) -> Self {
+        Self::from_known_str(&model).unwrap_or(Self::Other(model))
+    }
+}
+
+impl serde::Serialize for Model {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Model {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let model = String::deserialize(deserializer)?;
+        Ok(Self::from(model))
+    }
+}
+
+/// What to do with content checked by a content filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterAction {
+    /// Keep the content as-is.
+    Allow,
+
+    /// Fail with [`Error::Filtered`].
+    Reject,
+
+    /// Replace the content.
+    Redact(String),
+}
+
+/// A function that checks message content.
+#[derive(Clone)]
+pub(crate) struct ContentFilter(pub(crate) Arc<dyn Fn(&str) -> FilterAction + Send + Sync>);
+
+impl ContentFilter {
+    /// Filter the user messages of a request.
+    pub(crate) fn filter_request(&self, request: &ChatRequest) -> Result<ChatRequest, Error> {
+        let mut request = request.clone();
+        for message in request.messages.iter_mut() {
+            if message.role != Role::User {
+                continue;
+            }
+
+            match (self.0)(&message.content) {
+                FilterAction::Allow => {}
+                FilterAction::Reject => return Err(Error::Filtered),
+                FilterAction::Redact(content) => {
+                    message.content = content;
+                }
+            }
+        }
+
+        Ok(request)
+    }
+}
+
+impl std::fmt::Debug for ContentFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ContentFilter").finish()
+    }
+}
+
+/// The parsed body of a status handshake.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatusInfo {
+    /// The status, like "0".
+    pub status: Option<String>,
+
+    /// The models listed by the server.
+    ///
+    /// The models may be listed as strings, or as objects with a "model" or "id" field.
+    /// This is empty if the server did not list any.
+    pub models: Vec<String>,
+
+    /// All other fields of the body.
+    ///
+    /// If the body is not a JSON object, this is empty.
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl StatusInfo {
+    /// Parse a status response body.
+    ///
+    /// This never fails, as the format of the body is not documented.
+    pub(crate) fn from_body(body: &str) -> Self {
+        let mut extra = match serde_json::from_str(body) {
+            Ok(serde_json::Value::Object(body)) => body,
+            _ => return Self::default(),
+        };
+
+        let status = extra.remove("status").and_then(|status| match status {
+            serde_json::Value::String(status) => Some(status),
+            serde_json::Value::Number(status) => Some(status.to_string()),
+            _ => None,
+        });
+        let models = match extra.remove("models") {
+            Some(serde_json::Value::Array(models)) => models
+                .iter()
+                .filter_map(|model| {
+                    model
+                        .as_str()
+                        .or_else(|| model.get("model").and_then(|model| model.as_str()))
+                        .or_else(|| model.get("id").and_then(|model| model.as_str()))
+                })
+                .map(|model| model.to_string())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Self {
+            status,
+            models,
+            extra,
+        }
+    }
+}
+
+/// The role of a chat message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// "user"
+    User,
+
+    /// "assistant"
+    Assistant,
+
+    /// "system"
+    System,
+
+    /// A role not known by this library.
+    Other(String),
+}
+
+impl Role {
+    /// Get the wire string for this role.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::User => "user",
+            Self::Assistant => "assistant",
+            Self::System => "system",
+            Self::Other(role) => role.as_str(),
+        }
+    }
+
+    /// Get a human-readable label for this role, like "User".
+    fn label(&self) -> &str {
+        match self {
+            Self::User => "User",
+            Self::Assistant => "Assistant",
+            Self::System => "System",
+            Self::Other(role) => role.as_str(),
+        }
+    }
+
+    /// Parse a known role from a wire string.
+    fn from_known_str(role: &str) -> Option<Self> {
+        match role {
+            "user" => Some(Self::User),
+            "assistant" => Some(Self::Assistant),
+            "system" => Some(Self::System),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = Infallible;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from(role))
+    }
+}
+
+impl From<&str> for Role {
+    fn from(role: &str) -> Self {
+        Self::from_known_str(role).unwrap_or_else(|| Self::Other(role.into()))
+    }
+}
+
+impl From<String> for Role {
+    fn from(role: String) -> Self {
+        Self::from_known_str(&role).unwrap_or(Self::Other(role))
+    }
+}
+
+impl serde::Serialize for Role {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Role {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let role = String::deserialize(deserializer)?;
+        Ok(Self::from(role))
+    }
+}
+
+/// The action of a chat response message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// "success", a part of the answer
+    Answer,
+
+    /// "error"
+    Error,
+
+    /// An action not known by this library.
+    ///
+    /// These are not a part of the answer, so their content is dropped.
+    Other(String),
+}
+
+impl Action {
+    /// Get the wire string for this action.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Answer => "success",
+            Self::Error => "error",
+            Self::Other(action) => action.as_str(),
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<String> for Action {
+    fn from(action: String) -> Self {
+        match action.as_str() {
+            "success" => Self::Answer,
+            "error" => Self::Error,
+            _ => Self::Other(action),
+        }
+    }
+}
+
+impl serde::Serialize for Action {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Action {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let action = String::deserialize(deserializer)?;
+        Ok(Self::from(action))
+    }
+}
 
 /// A chat request
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+///
+/// The vqd is not a part of the JSON,
+/// so a request that was serialized and deserialized must get a new one,
+/// either from [`Client::resume_chat`](crate::Client::resume_chat) or [`ChatRequest::set_vqd`].
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct ChatRequest {
     /// Chat Messages
     pub messages: Vec<ChatMessage>,
 
     /// The model.
+    pub model: Model,
+
+    /// The sampling temperature.
     ///
-    /// Valid Choices:
-    /// * "claude-3-haiku-20240307"
-    /// * "claude-3-sonnet-20240229"
-    /// * "claude-3-5-sonnet-20240620"
-    /// * "meta-llama/Meta-Llama-3.1-70B-Instruct-Turbo"
-    /// * "mistralai/Mixtral-8x7B-Instruct-v0.1"
-    /// * "gpt-4o-mini"
-    /// * "gpt-4o"
-    ///
-    /// These choices were valid in the past,
-    /// but seem to no longer work:
-    /// * "meta-llama/Llama-3-70b-chat-hf"
-    /// * "gpt-3.5-turbo-0125"
-    /// * "gpt-4"
-    pub model: String,
+    /// This is only sent if set.
+    /// See [`ChatRequest::set_temperature`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+
+    /// The nucleus sampling probability mass.
+    ///
+    /// This is only sent if set.
+    /// See [`ChatRequest::set_top_p`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+
+    /// The maximum number of tokens to generate.
+    ///
+    /// This is only sent if set.
+    /// See [`ChatRequest::set_max_tokens`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
 
     /// A vqd token.
     /// Needed to make requests,
     /// but is not a part of the JSON.
     #[serde(skip)]
-    pub vqd: Option<String>,
+    vqd: Option<String>,
+
+    /// The latest "x-vqd-hash-1" challenge.
+    /// Given to the client's vqd hash solver, if it has one,
+    /// but is not a part of the JSON.
+    #[serde(skip)]
+    vqd_hash: Option<String>,
+}
+
+impl ChatRequest {
+    /// Create a new, empty [`ChatRequest`] for the given model.
+    ///
+    /// No vqd is set.
+    /// A vqd must be set before this request can be sent.
+    pub fn new(model: impl Into<Model>) -> Self {
+        let mut request = Self::from_messages(Vec::new());
+        request.model = model.into();
+        request
+    }
+
+    /// Create a new [`ChatRequest`] from a list of messages.
+    ///
+    /// The default model is used, and no vqd is set.
+    /// A vqd must be set before this request can be sent.
+    pub fn from_messages(messages: Vec<ChatMessage>) -> Self {
+        Self {
+            messages,
+            model: DEFAULT_MODEL,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            vqd: None,
+            vqd_hash: None,
+        }
+    }
+
+    /// Add a user message.
+    pub fn push_user(&mut self, content: impl Into<String>) -> &mut Self {
+        self.messages.push(ChatMessage::user(content));
+        self
+    }
+
+    /// Add an assistant message.
+    pub fn push_assistant(&mut self, content: impl Into<String>) -> &mut Self {
+        self.messages.push(ChatMessage::assistant(content));
+        self
+    }
+
+    /// Get the vqd token.
+    ///
+    /// This must be carried across turns.
+    /// [`Client::chat_and_append`](crate::Client::chat_and_append) does this automatically.
+    pub fn vqd(&self) -> Option<&str> {
+        self.vqd.as_deref()
+    }
+
+    /// Set the vqd token.
+    pub fn set_vqd(&mut self, vqd: String) {
+        self.vqd = Some(vqd);
+    }
+
+    /// Get the latest "x-vqd-hash-1" challenge.
+    pub fn vqd_hash(&self) -> Option<&str> {
+        self.vqd_hash.as_deref()
+    }
+
+    /// Set the latest "x-vqd-hash-1" challenge.
+    pub fn set_vqd_hash(&mut self, vqd_hash: String) {
+        self.vqd_hash = Some(vqd_hash);
+    }
+
+    /// Get the sampling temperature.
+    pub fn temperature(&self) -> Option<f32> {
+        self.temperature
+    }
+
+    /// Set the sampling temperature, or unset it with `None`.
+    ///
+    /// The endpoint does not currently document any generation parameters,
+    /// and it is not known to honor this one.
+    /// It is sent as "temperature" only when set, so unset requests are unchanged.
+    pub fn set_temperature(&mut self, temperature: Option<f32>) -> &mut Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Get the nucleus sampling probability mass.
+    pub fn top_p(&self) -> Option<f32> {
+        self.top_p
+    }
+
+    /// Set the nucleus sampling probability mass, or unset it with `None`.
+    ///
+    /// Like [`ChatRequest::set_temperature`],
+    /// this is sent as "top_p" only when set, and it is not known to be honored.
+    pub fn set_top_p(&mut self, top_p: Option<f32>) -> &mut Self {
+        self.top_p = top_p;
+        self
+    }
+
+    /// Get the maximum number of tokens to generate.
+    pub fn max_tokens(&self) -> Option<u32> {
+        self.max_tokens
+    }
+
+    /// Set the maximum number of tokens to generate, or unset it with `None`.
+    ///
+    /// Like [`ChatRequest::set_temperature`],
+    /// this is sent as "max_tokens" only when set, and it is not known to be honored.
+    pub fn set_max_tokens(&mut self, max_tokens: Option<u32>) -> &mut Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Check that the conversation can be sent.
+    ///
+    /// There must be at least one message.
+    /// An optional leading system message may be followed by user and assistant messages,
+    /// which must alternate starting and ending with a user message.
+    /// Retired models are rejected with [`Error::RetiredModel`].
+    /// This is checked by [`Client::chat`](crate::Client::chat) before sending,
+    /// as the server rejects malformed conversations with an unhelpful error.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.model.check_not_retired()?;
+
+        // This includes the system message, whose attachments would otherwise be dropped.
+        for (index, message) in self.messages.iter().enumerate() {
+            if !message.attachments.is_empty() && message.role != Role::User {
+                return Err(Error::InvalidConversation(format!(
+                    "only user messages may have attachments, found \"{}\" message at index {index}",
+                    message.role,
+                )));
+            }
+        }
+
+        let messages = match self.messages.split_first() {
+            Some((first, rest)) if first.role == Role::System => rest,
+            _ => &self.messages[..],
+        };
+        if messages.is_empty() {
+            return Err(Error::InvalidConversation(
+                "there are no user messages".into(),
+            ));
+        }
+
+        for (index, message) in messages.iter().enumerate() {
+            let expected = if index % 2 == 0 {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            if message.role != expected {
+                return Err(Error::InvalidConversation(format!(
+                    "expected a \"{expected}\" message at index {}, found \"{}\"",
+                    index + self.messages.len() - messages.len(),
+                    message.role
+                )));
+            }
+        }
+
+        if messages.len() % 2 == 0 {
+            return Err(Error::InvalidConversation(
+                "the last message must be from the user".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Render the messages as Markdown, like `**User:** Hello!`.
+    ///
+    /// Messages are separated by blank lines.
+    /// Message contents are kept as-is, since replies are usually Markdown already,
+    /// but the role labels are escaped.
+    pub fn to_markdown(&self) -> String {
+        self.render_transcript(|role| {
+            let mut label = String::with_capacity(role.len() + 5);
+            label.push_str("**");
+            for c in role.chars() {
+                if c.is_ascii_punctuation() {
+                    label.push('\\');
+                }
+                label.push(c);
+            }
+            label.push_str(":**");
+            label
+        })
+    }
+
+    /// Render the messages as plain text, like `User: Hello!`.
+    ///
+    /// Messages are separated by blank lines.
+    pub fn to_plaintext(&self) -> String {
+        self.render_transcript(|role| format!("{role}:"))
+    }
+
+    /// Render the messages, with each content prefixed by its formatted role label.
+    fn render_transcript(&self, format_label: impl Fn(&str) -> String) -> String {
+        let mut output = String::new();
+        for message in self.messages.iter() {
+            if !output.is_empty() {
+                output.push_str("\n\n");
+            }
+            output.push_str(&format_label(message.role.label()));
+            output.push(' ');
+            output.push_str(&message.content);
+        }
+
+        output
+    }
+
+    /// Serialize this request to the JSON body that is sent to the server.
+    ///
+    /// A leading system message is folded into the first user message,
+    /// and the vqd is not included, since it is sent as a header.
+    /// [`Client::chat`](crate::Client::chat) may also redact messages with a content filter first;
+    /// see [`Client::dry_run_chat`](crate::Client::dry_run_chat) to inspect the exact request.
+    pub fn to_wire_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&self.wire())
+    }
+
+    /// Get the total number of characters in all message contents.
+    pub fn content_len(&self) -> usize {
+        self.messages
+            .iter()
+            .map(|message| message.content.chars().count())
+            .sum()
+    }
+
+    /// Roughly estimate the number of tokens in all message contents.
+    ///
+    /// This assumes about 4 characters per token.
+    pub fn estimated_tokens(&self) -> usize {
+        self.content_len().div_ceil(4)
+    }
+
+    /// Drop the oldest user/assistant message pairs until the estimated tokens fit the budget.
+    ///
+    /// A leading system message is never dropped,
+    /// and neither is a trailing user message without a reply.
+    /// If `keep_first_turn` is true, the first user/assistant pair is also kept.
+    /// The result may still be over the budget if nothing else can be dropped.
+    pub fn truncate_to(&mut self, max_estimated_tokens: usize, keep_first_turn: bool) {
+        let mut start = match self.messages.first() {
+            Some(message) if message.role == Role::System => 1,
+            _ => 0,
+        };
+        if keep_first_turn && self.is_pair_at(start) {
+            start += 2;
+        }
+
+        let mut end = start;
+        let mut content_len = self.content_len();
+        while content_len.div_ceil(4) > max_estimated_tokens && self.is_pair_at(end) {
+            content_len -= self.messages[end..end + 2]
+                .iter()
+                .map(|message| message.content.chars().count())
+                .sum::<usize>();
+            end += 2;
+        }
+
+        self.messages.drain(start..end);
+    }
+
+    /// Remove the last assistant reply, so the user message before it can be sent again.
+    ///
+    /// The removed reply is returned.
+    /// If the last message is not an assistant reply to a user message,
+    /// [`Error::NothingToRegenerate`] is returned and the request is unchanged.
+    pub fn regenerate_last(&mut self) -> Result<ChatMessage, Error> {
+        let len = self.messages.len();
+        if len < 2 || !self.is_pair_at(len - 2) {
+            return Err(Error::NothingToRegenerate);
+        }
+
+        Ok(self.messages.pop().expect("missing assistant message"))
+    }
+
+    /// Check if there is a user message followed by an assistant message at the given index.
+    fn is_pair_at(&self, index: usize) -> bool {
+        match self.messages.get(index..index + 2) {
+            Some([user, assistant]) => user.role == Role::User && assistant.role == Role::Assistant,
+            _ => false,
+        }
+    }
+
+    /// Save this request as JSON.
+    ///
+    /// The vqd is not saved.
+    /// A new one must be fetched before a loaded request can be sent,
+    /// for example with [`Client::resume_chat`](crate::Client::resume_chat).
+    pub fn save_to_writer<W>(&self, writer: W) -> Result<(), serde_json::Error>
+    where
+        W: std::io::Write,
+    {
+        serde_json::to_writer(writer, self)
+    }
+
+    /// Load a request saved with [`ChatRequest::save_to_writer`].
+    ///
+    /// The loaded request has no vqd.
+    pub fn load_from_reader<R>(reader: R) -> Result<Self, serde_json::Error>
+    where
+        R: std::io::Read,
+    {
+        serde_json::from_reader(reader)
+    }
+
+    /// Insert or replace the system prompt.
+    ///
+    /// The system prompt is stored as a system message at index 0.
+    /// The backend does not accept system messages,
+    /// so when this request is sent the system prompt is prepended to the first user message instead.
+    pub fn set_system_prompt(&mut self, prompt: String) {
+        match self.messages.first_mut() {
+            Some(message) if message.role == Role::System => {
+                message.content = prompt;
+            }
+            _ => {
+                self.messages.insert(0, ChatMessage::system(prompt));
+            }
+        }
+    }
+
+    /// Get the request body, as it is sent to the server.
+    pub(crate) fn wire(&self) -> WireChatRequest<'_> {
+        WireChatRequest {
+            messages: self.wire_messages(),
+            model: &self.model,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+        }
+    }
+
+    /// Get the messages, as they are sent to the server.
+    ///
+    /// A leading system message is folded into the first user message.
+    fn wire_messages(&self) -> Cow<'_, [ChatMessage]> {
+        let (system, rest) = match self.messages.split_first() {
+            Some((system, rest)) if system.role == Role::System => (system, rest),
+            _ => return Cow::Borrowed(&self.messages),
+        };
+
+        let mut messages = rest.to_vec();
+        match messages
+            .iter_mut()
+            .find(|message| message.role == Role::User)
+        {
+            Some(message) => {
+                message.content = format!("{}\n\n{}", system.content, message.content);
+            }
+            None => {
+                messages.insert(0, ChatMessage::user(system.content.clone()));
+            }
+        }
+
+        Cow::Owned(messages)
+    }
+}
+
+/// The body of a chat request, as it is sent to the server.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct WireChatRequest<'a> {
+    #[serde(serialize_with = "serialize_wire_messages")]
+    messages: Cow<'a, [ChatMessage]>,
+    model: &'a Model,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+
+/// A chat message, as it is sent to the server.
+///
+/// Messages with attachments are sent as a list of content parts.
+#[derive(Debug, serde::Serialize)]
+struct WireChatMessage<'a> {
+    role: &'a Role,
+    content: WireContent<'a>,
+}
+
+/// The content of a [`WireChatMessage`].
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+enum WireContent<'a> {
+    Text(&'a str),
+    Parts(Vec<WireContentPart<'a>>),
+}
+
+/// A part of a [`WireContent`].
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum WireContentPart<'a> {
+    Text {
+        text: &'a str,
+    },
+    Image {
+        #[serde(rename = "mimeType")]
+        mime_type: &'a str,
+        image: String,
+    },
+}
+
+/// Serialize messages in the form the server expects.
+fn serialize_wire_messages<S>(
+    messages: &Cow<'_, [ChatMessage]>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_seq(messages.iter().map(|message| {
+        let content = if message.attachments.is_empty() {
+            WireContent::Text(&message.content)
+        } else {
+            let text = WireContentPart::Text {
+                text: &message.content,
+            };
+            let images = message
+                .attachments
+                .iter()
+                .map(|attachment| WireContentPart::Image {
+                    mime_type: &attachment.mime_type,
+                    image: attachment.data_url(),
+                });
+            WireContent::Parts(std::iter::once(text).chain(images).collect())
+        };
+
+        WireChatMessage {
+            role: &message.role,
+            content,
+        }
+    }))
+}
+
+// Sampling parameters are compared by their bits, so that this can be `Eq`.
+impl PartialEq for ChatRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.messages == other.messages
+            && self.model == other.model
+            && self.temperature.map(f32::to_bits) == other.temperature.map(f32::to_bits)
+            && self.top_p.map(f32::to_bits) == other.top_p.map(f32::to_bits)
+            && self.max_tokens == other.max_tokens
+            && self.vqd == other.vqd
+            && self.vqd_hash == other.vqd_hash
+    }
+}
+
+impl Eq for ChatRequest {}
+
+impl Default for ChatRequest {
+    fn default() -> Self {
+        Self::new(DEFAULT_MODEL)
+    }
+}
+
+impl From<Vec<ChatMessage>> for ChatRequest {
+    fn from(messages: Vec<ChatMessage>) -> Self {
+        Self::from_messages(messages)
+    }
+}
+
+impl FromIterator<ChatMessage> for ChatRequest {
+    /// Create a new [`ChatRequest`] from messages.
+    ///
+    /// See [`ChatRequest::from_messages`].
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = ChatMessage>,
+    {
+        Self::from_messages(iter.into_iter().collect())
+    }
+}
+
+impl Extend<ChatMessage> for ChatRequest {
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = ChatMessage>,
+    {
+        self.messages.extend(iter);
+    }
 }
 
 /// A chat message, for a chat request
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 pub struct ChatMessage {
     /// The role.
     ///
-    /// Valid Choices:
-    /// * "user"
-    /// * "assistant"
-    pub role: String,
+    /// A system message is only valid as the first message.
+    /// See [`ChatMessage::system`].
+    pub role: Role,
 
     /// The message content.
     pub content: String,
+
+    /// Files sent along with the message, like images.
+    ///
+    /// Only user messages may have attachments,
+    /// and only some models accept them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+}
+
+impl ChatMessage {
+    /// Create a new user message.
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::User,
+            content: content.into(),
+            attachments: Vec::new(),
+        }
+    }
+
+    /// Create a new assistant message.
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::Assistant,
+            content: content.into(),
+            attachments: Vec::new(),
+        }
+    }
+
+    /// Create a new system message.
+    ///
+    /// The backend does not accept system messages.
+    /// Instead, a system message at the start of a request
+    /// is prepended to the first user message when the request is sent.
+    /// System messages anywhere else are sent as-is.
+    pub fn system(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::System,
+            content: content.into(),
+            attachments: Vec::new(),
+        }
+    }
+
+    /// Create a new user message with an image.
+    ///
+    /// The image data is base64-encoded.
+    /// See [`ChatMessage::attachments`].
+    pub fn user_with_image(
+        content: impl Into<String>,
+        image: &[u8],
+        mime_type: impl Into<String>,
+    ) -> Self {
+        let mut message = Self::user(content);
+        message.attachments.push(Attachment::new(image, mime_type));
+        message
+    }
+
+    /// Check whether this is a user message.
+    ///
+    /// Unknown roles are compared case-insensitively, so "User" also matches.
+    pub fn is_user(&self) -> bool {
+        self.role.as_str().eq_ignore_ascii_case(Role::User.as_str())
+    }
+
+    /// Check whether this is an assistant message.
+    ///
+    /// Unknown roles are compared case-insensitively, so "Assistant" also matches.
+    pub fn is_assistant(&self) -> bool {
+        self.role
+            .as_str()
+            .eq_ignore_ascii_case(Role::Assistant.as_str())
+    }
+
+    /// Check whether this is a system message.
+    ///
+    /// Unknown roles are compared case-insensitively, so "System" also matches.
+    pub fn is_system(&self) -> bool {
+        self.role
+            .as_str()
+            .eq_ignore_ascii_case(Role::System.as_str())
+    }
+}
+
+/// A file attached to a chat message.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Attachment {
+    /// The mime type, like "image/png".
+    pub mime_type: String,
+
+    /// The base64-encoded file data.
+    pub data: String,
+}
+
+impl Attachment {
+    /// Make a new [`Attachment`], base64-encoding the given data.
+    pub fn new(data: &[u8], mime_type: impl Into<String>) -> Self {
+        Self {
+            mime_type: mime_type.into(),
+            data: BASE64_STANDARD.encode(data),
+        }
+    }
+
+    /// Get the attachment as a data url.
+    fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime_type, self.data)
+    }
 }
 
 /// A chat response message
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 pub struct ChatResponseMessage {
     /// The role.
-    pub role: Option<String>,
+    ///
+    /// This is usually only sent with the first message part.
+    pub role: Option<Role>,
 
     /// The message part.
     pub message: Option<String>,
 
-    /// The time the message was created?
+    /// The time the message was created, in seconds since the unix epoch.
+    ///
+    /// See [`ChatResponseMessage::created_time`].
     pub created: u64,
 
-    /// ?
+    /// The id of the response.
+    ///
+    /// Every message part of a response has the same id,
+    /// so it can be used to correlate parts, but not to tell them apart.
     pub id: String,
 
-    /// ?
-    pub action: String,
+    /// The kind of message.
+    ///
+    /// Only [`Action::Answer`] messages have content.
+    pub action: Action,
+
+    /// The model that generated the model.
+    pub model: String,
+}
+
+impl ChatResponseMessage {
+    /// Get the time the message was created.
+    ///
+    /// This returns `None` if the timestamp cannot be represented.
+    pub fn created_time(&self) -> Option<SystemTime> {
+        UNIX_EPOCH.checked_add(Duration::from_secs(self.created))
+    }
+
+    /// Get the id of the response.
+    ///
+    /// This is the same for every message part of a response.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// A complete chat response, along with metadata from the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectedResponse {
+    /// The new chat message.
+    pub message: ChatMessage,
 
-    /// The model that generated the model.
+    /// The model that generated the response.
+    ///
+    /// This may differ from the requested model.
     pub model: String,
+
+    /// The `created` time of the first response message.
+    pub first_created: u64,
+
+    /// The `created` time of the last response message.
+    pub last_created: u64,
 }
 
 /// A response stream for a chat.
+///
+/// This is `Send` and `Sync`, so it can be shared across tasks behind a mutex.
 pub struct ChatResponseStream {
-    stream: Pin<Box<dyn Stream<Item = Result<SseEvent, SseCodecError>> + Send>>,
+    stream: Pin<Box<dyn Stream<Item = std::io::Result<SseEvent>> + Send + Sync>>,
     done: bool,
+    complete: bool,
+    last_message_id: Option<String>,
+    requested_model: Model,
+    served_model: Option<String>,
+    strict_model: bool,
+    require_done: bool,
+    vqd: Option<String>,
+    vqd_hash: Option<String>,
+    bytes_received: usize,
+    cancelled: Option<Pin<Box<dyn Future<Output = ()> + Send + Sync>>>,
+    was_cancelled: bool,
+    content_filter: Option<ContentFilter>,
+    content_map: Option<Box<dyn FnMut(String) -> String + Send + Sync>>,
+    stop_sequences: Option<StopSequences>,
+    #[cfg(feature = "tokio")]
+    idle_timeout: Option<IdleTimeout>,
+    #[cfg(feature = "tokio")]
+    deadline: Option<Deadline>,
+    observer: Option<Arc<dyn Observer>>,
 }
 
 impl ChatResponseStream {
     /// Create a new [`ChatResponseStream`].
     pub(crate) fn new(
-        stream: Pin<Box<dyn Stream<Item = Result<SseEvent, SseCodecError>> + Send>>,
+        stream: Pin<Box<dyn Stream<Item = std::io::Result<SseEvent>> + Send + Sync>>,
+        requested_model: Model,
+        vqd: Option<String>,
+        vqd_hash: Option<String>,
     ) -> Self {
         Self {
             stream,
             done: false,
+            complete: false,
+            last_message_id: None,
+            requested_model,
+            served_model: None,
+            strict_model: false,
+            require_done: false,
+            vqd,
+            vqd_hash,
+            bytes_received: 0,
+            cancelled: None,
+            was_cancelled: false,
+            content_filter: None,
+            content_map: None,
+            stop_sequences: None,
+            #[cfg(feature = "tokio")]
+            idle_timeout: None,
+            #[cfg(feature = "tokio")]
+            deadline: None,
+            observer: None,
+        }
+    }
+
+    /// Create a response stream from a raw server-sent events response body.
+    ///
+    /// The stream has no new vqd.
+    /// This is only for benchmarks and tests, and is not a part of the public api.
+    #[doc(hidden)]
+    #[cfg(feature = "tokio")]
+    pub fn from_reader<R>(reader: R, requested_model: impl Into<Model>) -> Self
+    where
+        R: AsyncRead + Send + Sync + 'static,
+    {
+        let reader = SseStream::new(ReaderStream::new(reader), None);
+        Self::new(Box::pin(reader), requested_model.into(), None, None)
+    }
+
+    /// Fail this stream if no event arrives within the given duration.
+    ///
+    /// The timer starts when the stream is first polled,
+    /// and is reset every time an event arrives.
+    /// Once it expires, the stream yields an [`Error::IdleTimeout`] and then ends.
+    ///
+    /// This is not supported on wasm, and needs the `tokio` feature.
+    #[cfg(feature = "tokio")]
+    pub fn with_idle_timeout(mut self, duration: Duration) -> Self {
+        self.idle_timeout = Some(IdleTimeout {
+            duration,
+            sleep: None,
+        });
+        self
+    }
+
+    /// Fail this stream once the given duration has passed since it was first polled.
+    ///
+    /// Unlike [`ChatResponseStream::with_idle_timeout`], this is not reset when events arrive,
+    /// so it caps the total time a response may take.
+    /// Once it expires, the stream yields an [`Error::DeadlineExceeded`] and then ends.
+    /// Both timeouts may be used on the same stream.
+    ///
+    /// This is not supported on wasm, and needs the `tokio` feature.
+    #[cfg(feature = "tokio")]
+    pub fn with_deadline(mut self, duration: Duration) -> Self {
+        self.deadline = Some(Deadline {
+            duration,
+            sleep: None,
+        });
+        self
+    }
+
+    /// Fail this stream if the server serves a different model than the one requested.
+    ///
+    /// The stream yields an [`Error::ModelMismatch`] for the first mismatched event and then ends.
+    pub fn with_strict_model(mut self) -> Self {
+        self.strict_model = true;
+        self
+    }
+
+    /// Fail this stream if it ends without the `[DONE]` sentinel.
+    ///
+    /// By default, a stream that ends early, like when the connection drops, just ends.
+    /// With this, the stream instead yields an [`Error::IncompleteStream`] and then ends,
+    /// so collecting it returns an [`Error::PartialResponse`] with the truncated content.
+    /// Streams that end because they were cancelled are not affected.
+    pub fn with_require_done(mut self) -> Self {
+        self.require_done = true;
+        self
+    }
+
+    /// Transform each content part with the given function.
+    ///
+    /// Functions are applied in the order they are added,
+    /// after any content filter.
+    /// Content may be mapped to an empty string to drop it.
+    pub fn map_content<F>(mut self, mut f: F) -> Self
+    where
+        F: FnMut(String) -> String + Send + Sync + 'static,
+    {
+        self.content_map = Some(match self.content_map.take() {
+            Some(mut map) => Box::new(move |content| f(map(content))),
+            None => Box::new(f),
+        });
+        self
+    }
+
+    /// Remove the leading whitespace of the response.
+    ///
+    /// Whitespace is trimmed from the start of each content part
+    /// until the first one with other characters,
+    /// so leading whitespace split across several parts is removed too.
+    pub fn trim_leading(self) -> Self {
+        let mut started = false;
+        self.map_content(move |content| {
+            if started {
+                return content;
+            }
+
+            let trimmed = content.trim_start();
+            started = !trimmed.is_empty();
+            if trimmed.len() == content.len() {
+                content
+            } else {
+                trimmed.to_string()
+            }
+        })
+    }
+
+    /// End this stream once the content contains any of the given sequences.
+    ///
+    /// Only the content before the first match is yielded,
+    /// and the stream is treated as complete.
+    /// Sequences are matched across content parts,
+    /// so content that may be the start of a sequence is held back until the next part arrives.
+    /// Stop sequences are applied after any content mapping.
+    pub fn stop_at(mut self, sequences: Vec<String>) -> Self {
+        let sequences = sequences
+            .into_iter()
+            .filter(|sequence| !sequence.is_empty())
+            .collect();
+        self.stop_sequences = Some(StopSequences {
+            sequences,
+            held: String::new(),
+            last: None,
+        });
+        self
+    }
+
+    /// Check each content part with the given filter.
+    pub(crate) fn with_content_filter(mut self, filter: ContentFilter) -> Self {
+        self.content_filter = Some(filter);
+        self
+    }
+
+    /// Report content parts and errors to the given observer.
+    pub(crate) fn with_observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Get the model reported in the first event, if one was received.
+    pub fn served_model(&self) -> Option<&str> {
+        self.served_model.as_deref()
+    }
+
+    /// Check whether the server finished the response with the `[DONE]` sentinel.
+    ///
+    /// This is the definitive end-of-turn signal.
+    /// A stream that ended without it was cut off.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Get the id of the last response message.
+    ///
+    /// This is only set once the response is complete.
+    /// See [`ChatResponseStream::is_complete`].
+    pub fn last_message_id(&self) -> Option<&str> {
+        if !self.complete {
+            return None;
+        }
+
+        self.last_message_id.as_deref()
+    }
+
+    /// Check whether the served model differs from the requested model.
+    ///
+    /// This is `false` until the first event is received.
+    pub fn is_model_mismatch(&self) -> bool {
+        self.served_model
+            .as_deref()
+            .is_some_and(|served| served != self.requested_model.as_str())
+    }
+
+    /// Take the content held back by the stop sequences as a final message, if any.
+    fn flush_stop_sequences(&mut self) -> Option<ChatResponseMessage> {
+        let stop_sequences = self.stop_sequences.as_mut()?;
+        if stop_sequences.held.is_empty() {
+            return None;
         }
+
+        let mut message = stop_sequences.last.clone()?;
+        message.role = None;
+        message.message = Some(std::mem::take(&mut stop_sequences.held));
+        Some(message)
+    }
+
+    /// End this stream, dropping the http connection.
+    fn close(&mut self) {
+        self.done = true;
+        self.cancelled = None;
+        #[cfg(feature = "tokio")]
+        self.idle_timeout = None;
+        self.stream = Box::pin(tokio_stream::empty());
+    }
+
+    /// Get the new "x-vqd-hash-1" challenge returned by the server, if any.
+    ///
+    /// If this is set, it should be stored in the [`ChatRequest`] for the next turn.
+    pub fn vqd_hash(&self) -> Option<&str> {
+        self.vqd_hash.as_deref()
+    }
+
+    /// Get the number of content bytes received so far.
+    pub fn bytes_received(&self) -> usize {
+        self.bytes_received
+    }
+
+    /// End this stream once the given token is cancelled.
+    ///
+    /// Once cancelled, the stream yields `None` and the http connection is dropped.
+    /// Any content received before that is still yielded as normal.
+    /// If this is called more than once, the stream ends once any of the tokens is cancelled.
+    /// Streams from a [`Client`](crate::Client) also end when it is shut down,
+    /// see [`Client::shutdown`](crate::Client::shutdown).
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        let cancelled = Box::pin(async move { token.cancelled().await });
+        self.cancelled = Some(match self.cancelled.take() {
+            Some(previous) => Box::pin(async move {
+                select(previous, cancelled).await;
+            }),
+            None => cancelled,
+        });
+        self
+    }
+
+    /// Check whether this stream ended because its cancellation token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.was_cancelled
+    }
+
+    /// Get the new vqd token returned by the server, if any.
+    ///
+    /// If this is set, it should be stored in the [`ChatRequest`] for the next turn,
+    /// as the old token may no longer be valid.
+    pub fn vqd(&self) -> Option<&str> {
+        self.vqd.as_deref()
     }
 
     /// Consume this stream and get the new chat message.
+    ///
+    /// If the stream fails after some content was received,
+    /// an [`Error::PartialResponse`] is returned with that content.
     pub async fn collect_into_chat_message(&mut self) -> Result<ChatMessage, Error> {
+        let response = self.collect_into_response().await?;
+        Ok(response.message)
+    }
+
+    /// Consume this stream and get the new chat message,
+    /// calling the given function with each content part as it arrives.
+    ///
+    /// If the stream fails after some content was received,
+    /// an [`Error::PartialResponse`] is returned with that content.
+    pub async fn collect_with_callback<F>(&mut self, on_content: F) -> Result<ChatMessage, Error>
+    where
+        F: FnMut(&str),
+    {
+        let response = self.collect_into_response_with(on_content).await?;
+        Ok(response.message)
+    }
+
+    /// Consume this stream and get the new chat message, along with response metadata.
+    pub async fn collect_into_response(&mut self) -> Result<CollectedResponse, Error> {
+        self.collect_into_response_with(|_| {}).await
+    }
+
+    /// Consume this stream and get the new chat message, along with response metadata,
+    /// calling the given function with each content part.
+    async fn collect_into_response_with<F>(
+        &mut self,
+        mut on_content: F,
+    ) -> Result<CollectedResponse, Error>
+    where
+        F: FnMut(&str),
+    {
         let mut role = None;
         let mut content = String::new();
+        let mut metadata = None;
 
         while let Some(message) = self.next().await {
-            let message = message?;
+            let message = match message {
+                Ok(message) => message,
+                Err(error) => return Err(error.with_partial_content(content)),
+            };
 
             if let Some(message_role) = message.role {
                 role = Some(message_role);
             }
 
             if let Some(message) = message.message {
+                if !message.is_empty() {
+                    on_content(&message);
+                }
+                if content.capacity() - content.len() < message.len() {
+                    content.reserve(message.len() * RESERVED_CONTENT_PARTS);
+                }
+                content.push_str(&message);
+            }
+
+            let (_, _, last_created) =
+                metadata.get_or_insert((message.model, message.created, message.created));
+            *last_created = message.created;
+        }
+
+        let (model, first_created, last_created) = metadata.ok_or(Error::StreamEmpty)?;
+        Ok(CollectedResponse {
+            message: ChatMessage {
+                // Responses are from the assistant, even if the role is missing.
+                role: role.unwrap_or(Role::Assistant),
+                content,
+                attachments: Vec::new(),
+            },
+            model,
+            first_created,
+            last_created,
+        })
+    }
+
+    /// Consume this stream, writing each content part to the given writer as it arrives.
+    ///
+    /// The writer is flushed after each content part,
+    /// so interactive sinks like stdout or a socket see tokens promptly.
+    /// The assembled message is still returned at the end.
+    /// If the stream or writer fails after some content was received,
+    /// an [`Error::PartialResponse`] is returned with that content.
+    #[cfg(feature = "tokio")]
+    pub async fn write_all_to<W>(&mut self, writer: &mut W) -> Result<ChatMessage, Error>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut received = false;
+        let mut role = None;
+        let mut content = String::new();
+
+        while let Some(message) = self.next().await {
+            let message = match message {
+                Ok(message) => message,
+                Err(error) => return Err(error.with_partial_content(content)),
+            };
+            received = true;
+
+            if let Some(message_role) = message.role {
+                role = Some(message_role);
+            }
+
+            if let Some(message) = message.message.filter(|message| !message.is_empty()) {
+                let result = async {
+                    writer.write_all(message.as_bytes()).await?;
+                    writer.flush().await
+                }
+                .await;
                 content.push_str(&message);
+                if let Err(error) = result {
+                    return Err(Error::Io(error).with_partial_content(content));
+                }
             }
         }
 
-        // TODO: Throw error if not done?
+        if !received {
+            return Err(Error::StreamEmpty);
+        }
 
         Ok(ChatMessage {
-            role: role.ok_or(Error::StreamEmpty)?,
+            role: role.unwrap_or(Role::Assistant),
             content,
+            attachments: Vec::new(),
+        })
+    }
+
+    /// Consume this stream and get the message content.
+    ///
+    /// This ignores the role.
+    /// [`Error::StreamEmpty`] is only returned if no events were received.
+    /// If the stream fails after some content was received,
+    /// an [`Error::PartialResponse`] is returned with that content.
+    pub async fn collect_into_string(&mut self) -> Result<String, Error> {
+        let mut received = false;
+        let mut content = String::new();
+
+        while let Some(message) = self.next().await {
+            let message = match message {
+                Ok(message) => message,
+                Err(error) => return Err(error.with_partial_content(content)),
+            };
+            received = true;
+
+            if let Some(message) = message.message {
+                content.push_str(&message);
+            }
+        }
+
+        if !received {
+            return Err(Error::StreamEmpty);
+        }
+
+        Ok(content)
+    }
+
+    /// Stream the response messages into a channel from a background task.
+    ///
+    /// Only messages with content are sent.
+    /// At most `capacity` messages are buffered before the task waits for the receiver.
+    /// If the receiver is dropped, the stream is still drained.
+    /// Once the stream ends, `on_finish` is called with the complete message,
+    /// or with `None` if the stream failed or was cancelled.
+    /// A stream error is sent on the channel after `on_finish` is called.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is 0, or if called outside of a tokio runtime.
+    #[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+    pub fn into_channel<F>(
+        mut self,
+        capacity: usize,
+        on_finish: F,
+    ) -> tokio::sync::mpsc::Receiver<Result<ChatResponseMessage, Error>>
+    where
+        F: FnOnce(Option<ChatMessage>) + Send + 'static,
+    {
+        let (tx, rx) = tokio::sync::mpsc::channel(capacity);
+        tokio::spawn(async move {
+            let mut received = false;
+            let mut role = None;
+            let mut content = String::new();
+
+            let result = loop {
+                let message = match self.next().await {
+                    Some(Ok(message)) => message,
+                    Some(Err(error)) => break Err(error),
+                    None => break Ok(()),
+                };
+                received = true;
+
+                if let Some(message_role) = message.role.clone() {
+                    role = Some(message_role);
+                }
+                match message.message.as_deref() {
+                    Some(part) if !part.is_empty() => {
+                        content.push_str(part);
+
+                        // Keep draining even if nobody is listening,
+                        // so the message is still completed.
+                        let _ = tx.send(Ok(message)).await.is_ok();
+                    }
+                    _ => {}
+                }
+            };
+
+            let result = match result {
+                Ok(()) if !received => Err(Error::StreamEmpty),
+                result => result,
+            };
+            match result {
+                Ok(()) if self.is_cancelled() => on_finish(None),
+                Ok(()) => on_finish(Some(ChatMessage {
+                    role: role.unwrap_or(Role::Assistant),
+                    content,
+                    attachments: Vec::new(),
+                })),
+                Err(error) => {
+                    on_finish(None);
+                    let _ = tx.send(Err(error)).await.is_ok();
+                }
+            }
+        });
+
+        rx
+    }
+
+    /// Stream structured progress events into a channel from a background task.
+    ///
+    /// A [`ProgressEvent::Started`] is sent with the first message,
+    /// followed by a [`ProgressEvent::Delta`] for each content part.
+    /// The last event is always one of [`ProgressEvent::Finished`], [`ProgressEvent::Cancelled`],
+    /// or [`ProgressEvent::Error`].
+    /// At most `capacity` events are buffered before the task waits for the receiver.
+    /// If the receiver is dropped, the response is aborted.
+    ///
+    /// # Panics
+    /// Panics if `capacity` is 0, or if called outside of a tokio runtime.
+    #[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+    pub fn into_progress_channel(
+        mut self,
+        capacity: usize,
+    ) -> tokio::sync::mpsc::Receiver<ProgressEvent> {
+        let (tx, rx) = tokio::sync::mpsc::channel(capacity);
+        tokio::spawn(async move {
+            let mut started = false;
+            let mut role = None;
+            let mut content = String::new();
+            let mut tokens_received = 0;
+
+            let event = loop {
+                let message = match self.next().await {
+                    Some(Ok(message)) => message,
+                    Some(Err(error)) => {
+                        break ProgressEvent::Error(error.with_partial_content(content))
+                    }
+                    None if self.is_cancelled() => break ProgressEvent::Cancelled,
+                    None if !started => break ProgressEvent::Error(Error::StreamEmpty),
+                    None => {
+                        break ProgressEvent::Finished {
+                            message: ChatMessage {
+                                role: role.unwrap_or(Role::Assistant),
+                                content,
+                                attachments: Vec::new(),
+                            },
+                        }
+                    }
+                };
+
+                if !started {
+                    started = true;
+                    let event = ProgressEvent::Started {
+                        model: message.model.clone(),
+                    };
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+                if let Some(message_role) = message.role {
+                    role = Some(message_role);
+                }
+                if let Some(part) = message.message.filter(|part| !part.is_empty()) {
+                    content.push_str(&part);
+                    tokens_received += 1;
+                    let event = ProgressEvent::Delta {
+                        content: part,
+                        tokens_received,
+                        bytes_received: self.bytes_received(),
+                    };
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            };
+
+            let _ = tx.send(event).await.is_ok();
+        });
+
+        rx
+    }
+
+    /// Convert this stream into a stream of content parts.
+    ///
+    /// Empty content parts and events without content are skipped.
+    pub fn text_stream(self) -> impl Stream<Item = Result<String, Error>> {
+        self.filter_map(|message| match message {
+            Ok(message) => message
+                .message
+                .filter(|content| !content.is_empty())
+                .map(Ok),
+            Err(error) => Some(Err(error)),
+        })
+    }
+
+    /// Convert this stream into a stream of JSON lines.
+    ///
+    /// Each message is serialized as compact JSON followed by a newline,
+    /// so the lines can be written directly to stdout for other tools.
+    /// The stream ends when the response ends; the `[DONE]` sentinel is not sent.
+    pub fn into_jsonl_stream(self) -> impl Stream<Item = Result<String, Error>> {
+        self.map(|message| {
+            let mut line = serde_json::to_string(&message?).map_err(Error::InvalidSseEventData)?;
+            line.push('\n');
+            Ok(line)
         })
     }
+
+    /// Convert this stream into a reader of the concatenated content.
+    ///
+    /// The reader ends when the response ends.
+    /// Stream errors are returned as [`std::io::Error`]s wrapping an [`Error`].
+    #[cfg(feature = "tokio")]
+    pub fn into_async_read(self) -> impl AsyncBufRead {
+        StreamReader::new(
+            self.text_stream()
+                .map(|content| content.map(Bytes::from).map_err(std::io::Error::other)),
+        )
+    }
+
+    /// Convert this stream into a stream of OpenAI-style server-sent events.
+    pub fn into_openai_sse_stream(self) -> crate::compat::openai::OpenAiSseStream {
+        crate::compat::openai::OpenAiSseStream::new(self)
+    }
+
+    /// Convert this stream into a stream of [`ChatEvent`]s.
+    pub fn into_event_stream(self) -> ChatEventStream {
+        ChatEventStream::new(self)
+    }
 }
 
 impl std::fmt::Debug for ChatResponseStream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ChatResponseStream")
             .field("done", &self.done)
+            .field("complete", &self.complete)
+            .field("last_message_id", &self.last_message_id)
+            .field("requested_model", &self.requested_model)
+            .field("served_model", &self.served_model)
+            .field("vqd", &self.vqd)
+            .field("vqd_hash", &self.vqd_hash)
+            .field("bytes_received", &self.bytes_received)
+            .field("was_cancelled", &self.was_cancelled)
             .finish()
     }
 }
 
-impl Stream for ChatResponseStream {
-    type Item = Result<ChatResponseMessage, Error>;
-
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+impl ChatResponseStream {
+    /// Poll for the next message, without calling the observer.
+    fn poll_message(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<ChatResponseMessage, Error>>> {
         if self.done {
             return Poll::Ready(None);
         }
 
-        let event = match ready!(self.stream.as_mut().poll_next(cx)) {
+        if let Some(cancelled) = self.cancelled.as_mut() {
+            if cancelled.as_mut().poll(cx).is_ready() {
+                self.close();
+                self.was_cancelled = true;
+                return Poll::Ready(None);
+            }
+        }
+
+        #[cfg(feature = "tokio")]
+        if let Some(deadline) = self.deadline.as_mut() {
+            let duration = deadline.duration;
+            let sleep = deadline
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(duration)));
+            if sleep.as_mut().poll(cx).is_ready() {
+                self.close();
+                return Poll::Ready(Some(Err(Error::DeadlineExceeded)));
+            }
+        }
+
+        let event = match self.stream.as_mut().poll_next(cx) {
+            Poll::Ready(event) => event,
+            Poll::Pending => {
+                #[cfg(feature = "tokio")]
+                if let Some(idle_timeout) = self.idle_timeout.as_mut() {
+                    let duration = idle_timeout.duration;
+                    let sleep = idle_timeout
+                        .sleep
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(duration)));
+                    if sleep.as_mut().poll(cx).is_ready() {
+                        self.close();
+                        return Poll::Ready(Some(Err(Error::IdleTimeout)));
+                    }
+                }
+                return Poll::Pending;
+            }
+        };
+        #[cfg(feature = "tokio")]
+        if let Some(idle_timeout) = self.idle_timeout.as_mut() {
+            let deadline = Instant::now() + idle_timeout.duration;
+            if let Some(sleep) = idle_timeout.sleep.as_mut() {
+                sleep.as_mut().reset(deadline);
+            }
+        }
+        let event = match event {
             Some(event) => event,
             None => {
+                if let Some(message) = self.flush_stop_sequences() {
+                    return Poll::Ready(Some(Ok(message)));
+                }
+                self.close();
+                if self.require_done {
+                    return Poll::Ready(Some(Err(Error::IncompleteStream)));
+                }
                 return Poll::Ready(None);
             }
         };
@@ -147,11 +1905,334 @@ impl Stream for ChatResponseStream {
 
         if data == "[DONE]" {
             self.done = true;
-            return Poll::Ready(None);
+            self.complete = true;
+            return Poll::Ready(self.flush_stop_sequences().map(Ok));
+        }
+
+        let mut message = serde_json::from_str::<WireResponseMessage>(&data)
+            .map_err(Error::InvalidSseEventData)?
+            .into_message(&data)?;
+        match message.action {
+            Action::Answer => {}
+            // Error actions were handled above.
+            Action::Error => {}
+            // Metadata and keepalive events are not a part of the answer.
+            Action::Other(_) => message.message = None,
+        }
+        if self.served_model.is_none() {
+            self.served_model = Some(message.model.clone());
+        }
+        if self.last_message_id.as_deref() != Some(message.id.as_str()) {
+            self.last_message_id = Some(message.id.clone());
+        }
+        if self.strict_model && message.model != self.requested_model.as_str() {
+            let error = Error::ModelMismatch {
+                requested: self.requested_model.to_string(),
+                served: message.model,
+            };
+            self.close();
+            return Poll::Ready(Some(Err(error)));
+        }
+        if let Some(content) = message.message.as_ref() {
+            self.bytes_received += content.len();
+        }
+        let action = match (self.content_filter.as_ref(), message.message.as_deref()) {
+            (Some(filter), Some(content)) if !content.is_empty() => (filter.0)(content),
+            _ => FilterAction::Allow,
+        };
+        match action {
+            FilterAction::Allow => {}
+            FilterAction::Reject => {
+                self.close();
+                return Poll::Ready(Some(Err(Error::Filtered)));
+            }
+            FilterAction::Redact(content) => {
+                message.message = Some(content);
+            }
+        }
+        if let Some(map) = self.content_map.as_mut() {
+            message.message = message.message.map(map);
+        }
+        if let Some(stop_sequences) = self.stop_sequences.as_mut() {
+            if let Some(content) = message.message.take() {
+                let (content, stopped) = stop_sequences.push(&content);
+                message.message = Some(content);
+                if stopped {
+                    self.close();
+                    self.complete = true;
+                    return Poll::Ready(Some(Ok(message)));
+                }
+            }
+            stop_sequences.last = Some(message.clone());
+        }
+
+        Poll::Ready(Some(Ok(message)))
+    }
+}
+
+impl Stream for ChatResponseStream {
+    type Item = Result<ChatResponseMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.as_mut().poll_message(cx));
+        if let Some(observer) = self.observer.as_ref() {
+            match item.as_ref() {
+                Some(Ok(message)) => {
+                    if let Some(content) = message.message.as_deref() {
+                        observer.on_token(content);
+                    }
+                }
+                Some(Err(error)) => observer.on_error(error),
+                None => {}
+            }
+        }
+
+        Poll::Ready(item)
+    }
+}
+
+impl FusedStream for ChatResponseStream {
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+/// A chat response event, as sent by the server.
+///
+/// Strings borrow from the event data unless they have escapes,
+/// so only the fields of a [`ChatResponseMessage`] are allocated.
+/// Error events have none of the message fields, so those are optional here,
+/// which lets every event be parsed once.
+#[derive(Debug, serde::Deserialize)]
+struct WireResponseMessage<'a> {
+    #[serde(borrow, default)]
+    role: Option<WireStr<'a>>,
+
+    #[serde(borrow, default)]
+    message: Option<WireStr<'a>>,
+
+    #[serde(default)]
+    created: Option<u64>,
+
+    #[serde(borrow, default)]
+    id: Option<WireStr<'a>>,
+
+    #[serde(borrow)]
+    action: WireStr<'a>,
+
+    #[serde(borrow, default)]
+    model: Option<WireStr<'a>>,
+
+    /// The type of an error event, like "ERR_CONVERSATION_LIMIT"
+    #[serde(borrow, default, rename = "type")]
+    ty: Option<WireStr<'a>>,
+}
+
+impl WireResponseMessage<'_> {
+    /// Convert this event into a message, or into an error for error events.
+    ///
+    /// The raw event data is used as the error message if there is no better one.
+    fn into_message(self, data: &str) -> Result<ChatResponseMessage, Error> {
+        let action = match &*self.action.0 {
+            "success" => Action::Answer,
+            "error" => {
+                let ty = self.ty.map(WireStr::into_owned);
+                let message = self
+                    .message
+                    .map(WireStr::into_owned)
+                    .or_else(|| ty.clone())
+                    .unwrap_or_else(|| data.to_string());
+                return Err(Error::ServerError { message, ty });
+            }
+            action => Action::Other(action.to_string()),
+        };
+        let missing_field =
+            |field| Error::InvalidSseEventData(serde::de::Error::missing_field(field));
+
+        Ok(ChatResponseMessage {
+            role: self.role.map(|role| Role::from(&*role.0)),
+            message: self.message.map(WireStr::into_owned),
+            created: self.created.ok_or_else(|| missing_field("created"))?,
+            id: self.id.ok_or_else(|| missing_field("id"))?.into_owned(),
+            action,
+            model: self
+                .model
+                .ok_or_else(|| missing_field("model"))?
+                .into_owned(),
+        })
+    }
+}
+
+/// A string in a [`WireResponseMessage`], borrowed if possible.
+///
+/// A bare `Option<Cow<str>>` field is always deserialized as owned,
+/// so this wrapper is needed to borrow optional strings.
+#[derive(Debug, serde::Deserialize)]
+struct WireStr<'a>(#[serde(borrow)] Cow<'a, str>);
+
+impl WireStr<'_> {
+    /// Get the string, allocating if it is borrowed.
+    fn into_owned(self) -> String {
+        self.0.into_owned()
+    }
+}
+
+/// The stop sequence state of a [`ChatResponseStream`].
+struct StopSequences {
+    sequences: Vec<String>,
+
+    /// Content that may be the start of a stop sequence
+    held: String,
+
+    /// The last message, used as a template for flushing held content
+    last: Option<ChatResponseMessage>,
+}
+
+impl StopSequences {
+    /// Add a content part, returning the content that can be yielded and whether a sequence matched.
+    fn push(&mut self, content: &str) -> (String, bool) {
+        self.held.push_str(content);
+
+        let matched = self
+            .sequences
+            .iter()
+            .filter_map(|sequence| self.held.find(sequence.as_str()))
+            .min();
+        if let Some(index) = matched {
+            self.held.truncate(index);
+            return (std::mem::take(&mut self.held), true);
+        }
+
+        // Hold back the longest end of the content that starts a sequence.
+        let keep = self
+            .sequences
+            .iter()
+            .filter_map(|sequence| {
+                (1..sequence.len()).rev().find(|&len| {
+                    sequence.is_char_boundary(len) && self.held.ends_with(&sequence[..len])
+                })
+            })
+            .max()
+            .unwrap_or(0);
+        let rest = self.held.split_off(self.held.len() - keep);
+        (std::mem::replace(&mut self.held, rest), false)
+    }
+}
+
+/// The idle timeout state of a [`ChatResponseStream`].
+#[cfg(feature = "tokio")]
+struct IdleTimeout {
+    duration: Duration,
+
+    /// The timer, started the first time the stream has to wait for an event
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+/// The deadline state of a [`ChatResponseStream`].
+#[cfg(feature = "tokio")]
+struct Deadline {
+    duration: Duration,
+
+    /// The timer, started on the first poll
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+/// A progress event from [`ChatResponseStream::into_progress_channel`].
+#[derive(Debug)]
+pub enum ProgressEvent {
+    /// The first message arrived.
+    Started {
+        /// The model that is serving the response
+        model: String,
+    },
+
+    /// A part of the message content.
+    Delta {
+        /// The content part
+        content: String,
+
+        /// The number of content parts received so far, including this one
+        tokens_received: usize,
+
+        /// The number of content bytes received so far, including this part
+        bytes_received: usize,
+    },
+
+    /// The response is complete.
+    Finished {
+        /// The complete message
+        message: ChatMessage,
+    },
+
+    /// The response was cancelled.
+    Cancelled,
+
+    /// The response failed.
+    ///
+    /// If content was received first, this is an [`Error::PartialResponse`].
+    Error(Error),
+}
+
+/// An event from a [`ChatEventStream`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatEvent {
+    /// The role of the responder.
+    ///
+    /// This is sent once, before any content.
+    RoleReceived(Role),
+
+    /// A part of the message content.
+    Content(String),
+}
+
+/// A response stream for a chat, which yields [`ChatEvent`]s.
+///
+/// This is a richer view over a [`ChatResponseStream`].
+#[derive(Debug)]
+pub struct ChatEventStream {
+    stream: ChatResponseStream,
+    role_sent: bool,
+    pending_content: Option<String>,
+}
+
+impl ChatEventStream {
+    /// Create a new [`ChatEventStream`].
+    fn new(stream: ChatResponseStream) -> Self {
+        Self {
+            stream,
+            role_sent: false,
+            pending_content: None,
+        }
+    }
+}
+
+impl Stream for ChatEventStream {
+    type Item = Result<ChatEvent, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(content) = self.pending_content.take() {
+            return Poll::Ready(Some(Ok(ChatEvent::Content(content))));
         }
 
-        Poll::Ready(Some(
-            serde_json::from_str(&data).map_err(Error::InvalidSseEventData),
-        ))
+        loop {
+            let message = match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(message)) => message,
+                Some(Err(error)) => return Poll::Ready(Some(Err(error))),
+                None => return Poll::Ready(None),
+            };
+            let content = message.message.filter(|content| !content.is_empty());
+
+            if !self.role_sent {
+                if let Some(role) = message.role {
+                    self.role_sent = true;
+                    self.pending_content = content;
+                    return Poll::Ready(Some(Ok(ChatEvent::RoleReceived(role))));
+                }
+            }
+
+            if let Some(content) = content {
+                return Poll::Ready(Some(Ok(ChatEvent::Content(content))));
+            }
+        }
     }
 }
@@ -0,0 +1,21 @@
+use crate::Error;
+
+/// Hooks that are called as a client sends requests and reads responses.
+///
+/// This can be used to collect metrics, like request counts or token rates.
+/// All hooks do nothing by default, so only the needed ones have to be implemented.
+/// Hooks are called inline, so they should be fast and must not block.
+/// See [`ClientBuilder::observer`](crate::ClientBuilder::observer).
+pub trait Observer: std::fmt::Debug + Send + Sync {
+    /// Called before each http request is sent, including retries.
+    fn on_request(&self, _request: &reqwest::Request) {}
+
+    /// Called with each content part of a chat response.
+    fn on_token(&self, _content: &str) {}
+
+    /// Called when a request or a chat response stream fails.
+    fn on_error(&self, _error: &Error) {}
+
+    /// Called when a rejected vqd is replaced with a new one.
+    fn on_vqd_refresh(&self) {}
+}
@@ -0,0 +1,122 @@
+//! Building prompts from templates.
+
+use crate::ChatMessage;
+use crate::ChatRequest;
+use crate::Error;
+use crate::Model;
+use crate::Role;
+use std::collections::HashMap;
+
+/// A builder for the messages of a chat.
+///
+/// Message contents are templates, where `{name}` is replaced with the value of the variable `name`.
+/// Use `{{` and `}}` for literal braces.
+/// Templates are only rendered when the messages are built,
+/// so variables may be set in any order.
+#[derive(Debug, Default, Clone)]
+pub struct PromptBuilder {
+    messages: Vec<(Role, String)>,
+    variables: HashMap<String, String>,
+}
+
+impl PromptBuilder {
+    /// Make a new, empty [`PromptBuilder`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a system message template.
+    ///
+    /// A system message is only valid as the first message.
+    pub fn system(mut self, template: impl Into<String>) -> Self {
+        self.messages.push((Role::System, template.into()));
+        self
+    }
+
+    /// Add a user message template.
+    pub fn user(mut self, template: impl Into<String>) -> Self {
+        self.messages.push((Role::User, template.into()));
+        self
+    }
+
+    /// Add an assistant message template.
+    pub fn assistant(mut self, template: impl Into<String>) -> Self {
+        self.messages.push((Role::Assistant, template.into()));
+        self
+    }
+
+    /// Set the value of a variable.
+    ///
+    /// Values are inserted as-is, and are not rendered as templates.
+    pub fn var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.variables.insert(name.into(), value.into());
+        self
+    }
+
+    /// Render the templates into messages.
+    ///
+    /// An [`Error::InvalidTemplate`] is returned if a template is malformed
+    /// or uses a variable that was not set.
+    pub fn build_messages(&self) -> Result<Vec<ChatMessage>, Error> {
+        self.messages
+            .iter()
+            .map(|(role, template)| {
+                Ok(ChatMessage {
+                    role: role.clone(),
+                    content: render(template, &self.variables)?,
+                    attachments: Vec::new(),
+                })
+            })
+            .collect()
+    }
+
+    /// Render the templates into a request for the given model.
+    ///
+    /// No vqd is set.
+    /// See [`PromptBuilder::build_messages`].
+    pub fn build_request(&self, model: impl Into<Model>) -> Result<ChatRequest, Error> {
+        let mut request = ChatRequest::from_messages(self.build_messages()?);
+        request.model = model.into();
+        Ok(request)
+    }
+}
+
+/// Render a template with the given variables.
+fn render(template: &str, variables: &HashMap<String, String>) -> Result<String, Error> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(index) = rest.find(['{', '}']) {
+        output.push_str(&rest[..index]);
+        let brace = &rest[index..];
+
+        if let Some(after) = brace
+            .strip_prefix("{{")
+            .or_else(|| brace.strip_prefix("}}"))
+        {
+            output.push_str(&brace[..1]);
+            rest = after;
+            continue;
+        }
+        if brace.starts_with('}') {
+            return Err(Error::InvalidTemplate(format!(
+                "unmatched \"}}\" at byte {}",
+                template.len() - brace.len()
+            )));
+        }
+
+        let (name, after) = brace[1..].split_once('}').ok_or_else(|| {
+            Error::InvalidTemplate(format!(
+                "unclosed \"{{\" at byte {}",
+                template.len() - brace.len()
+            ))
+        })?;
+        let value = variables
+            .get(name)
+            .ok_or_else(|| Error::InvalidTemplate(format!("missing variable \"{name}\"")))?;
+        output.push_str(value);
+        rest = after;
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
@@ -0,0 +1,165 @@
+use bytes::Buf;
+use bytes::Bytes;
+use bytes::BytesMut;
+use std::pin::Pin;
+use std::task::ready;
+use std::task::Context;
+use std::task::Poll;
+use tokio_stream::Stream;
+
+/// The initial buffer capacity.
+const DEFAULT_CAPACITY: usize = 8 * 1024;
+
+/// A server-sent event.
+///
+/// Only the data is kept, since the server does not use event names or ids.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SseEvent {
+    /// The data, with multiple data lines joined by newlines
+    ///
+    /// This is `None` if the event had no data lines, like a bare "event: ping".
+    pub(crate) data: Option<String>,
+}
+
+/// An incremental server-sent events decoder.
+///
+/// This follows the parsing rules of the html spec,
+/// but ignores the "event", "id", and "retry" fields.
+#[derive(Debug, Default)]
+struct SseDecoder {
+    /// The data of the event being decoded
+    data: Option<String>,
+
+    /// Whether the event being decoded had any fields
+    has_fields: bool,
+
+    /// Whether the first line was decoded, after which a byte order mark is no longer stripped
+    started: bool,
+}
+
+impl SseDecoder {
+    /// Decode the next complete event from the buffer, if there is one.
+    ///
+    /// At eof, a trailing line without a line ending is still decoded,
+    /// but an event without a trailing blank line is discarded.
+    fn decode(&mut self, buffer: &mut BytesMut, eof: bool) -> std::io::Result<Option<SseEvent>> {
+        loop {
+            if !self.started {
+                if buffer.len() < 3 && !eof && b"\xEF\xBB\xBF".starts_with(&buffer[..]) {
+                    return Ok(None);
+                }
+                if buffer.starts_with(b"\xEF\xBB\xBF") {
+                    buffer.advance(3);
+                }
+                self.started = true;
+            }
+
+            let (line, ending) = match buffer.iter().position(|&b| b == b'\r' || b == b'\n') {
+                // A "\r" may be the start of a "\r\n" that is split across chunks.
+                Some(index) if buffer[index] == b'\r' && index + 1 == buffer.len() && !eof => {
+                    return Ok(None)
+                }
+                Some(index) if buffer[index..].starts_with(b"\r\n") => (index, 2),
+                Some(index) => (index, 1),
+                None if eof && !buffer.is_empty() => (buffer.len(), 0),
+                None => return Ok(None),
+            };
+            let line = buffer.split_to(line);
+            buffer.advance(ending);
+
+            let line = std::str::from_utf8(&line)
+                .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+            if line.is_empty() {
+                if std::mem::take(&mut self.has_fields) {
+                    return Ok(Some(SseEvent {
+                        data: self.data.take(),
+                    }));
+                }
+                continue;
+            }
+            if line.starts_with(':') {
+                continue;
+            }
+
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line, ""),
+            };
+            self.has_fields = true;
+            if field == "data" {
+                match self.data.as_mut() {
+                    Some(data) => {
+                        data.push('\n');
+                        data.push_str(value);
+                    }
+                    None => self.data = Some(value.to_string()),
+                }
+            }
+        }
+    }
+}
+
+/// A stream of sse events, decoded from a stream of byte chunks.
+///
+/// This only uses `futures` traits, so it does not need a tokio runtime.
+pub(crate) struct SseStream<S> {
+    stream: Pin<Box<S>>,
+    decoder: SseDecoder,
+    buffer: BytesMut,
+    eof: bool,
+    done: bool,
+}
+
+impl<S> SseStream<S> {
+    /// Make a new [`SseStream`] with the given initial buffer capacity.
+    ///
+    /// By default, the capacity is 8 KiB.
+    pub(crate) fn new(stream: S, capacity: Option<usize>) -> Self {
+        Self {
+            stream: Box::pin(stream),
+            decoder: SseDecoder::default(),
+            buffer: BytesMut::with_capacity(capacity.unwrap_or(DEFAULT_CAPACITY)),
+            eof: false,
+            done: false,
+        }
+    }
+}
+
+impl<S, E> Stream for SseStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    type Item = std::io::Result<SseEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if this.done {
+                return Poll::Ready(None);
+            }
+
+            match this.decoder.decode(&mut this.buffer, this.eof) {
+                Ok(Some(event)) => return Poll::Ready(Some(Ok(event))),
+                Ok(None) if this.eof => {
+                    this.done = true;
+                    return Poll::Ready(None);
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(error)));
+                }
+            }
+
+            match ready!(this.stream.as_mut().poll_next(cx)) {
+                Some(Ok(chunk)) => this.buffer.extend_from_slice(&chunk),
+                Some(Err(error)) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(std::io::Error::other(error))));
+                }
+                None => this.eof = true,
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+#[cfg(feature = "replay")]
+mod replay;
+
+#[cfg(feature = "replay")]
+pub(crate) use self::replay::RecordingTransport;
+#[cfg(feature = "replay")]
+pub(crate) use self::replay::ReplayTransport;
+use crate::Error;
+use std::future::Future;
+use std::pin::Pin;
+
+/// The future returned by a [`Transport`].
+#[cfg(not(target_arch = "wasm32"))]
+pub type TransportFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<reqwest::Response, Error>> + Send + 'a>>;
+
+/// The future returned by a [`Transport`].
+#[cfg(target_arch = "wasm32")]
+pub type TransportFuture<'a> = Pin<Box<dyn Future<Output = Result<reqwest::Response, Error>> + 'a>>;
+
+/// A way to send http requests.
+///
+/// Requests are still built with the client's [`reqwest::Client`],
+/// but a transport decides how they are sent and where the responses come from.
+/// This allows using a different http backend, or serving canned responses in tests.
+/// See [`ClientBuilder::transport`](crate::ClientBuilder::transport).
+pub trait Transport: std::fmt::Debug + Send + Sync {
+    /// Send a request and get the response.
+    fn execute(&self, request: reqwest::Request) -> TransportFuture<'_>;
+
+    /// Send the status handshake request.
+    ///
+    /// By default, this calls [`Transport::execute`].
+    fn status(&self, request: reqwest::Request) -> TransportFuture<'_> {
+        self.execute(request)
+    }
+
+    /// Send a chat request.
+    ///
+    /// The response body is a stream of server-sent events.
+    /// By default, this calls [`Transport::execute`].
+    fn chat(&self, request: reqwest::Request) -> TransportFuture<'_> {
+        self.execute(request)
+    }
+}
+
+/// A transport that sends requests over the network with reqwest.
+///
+/// This is the default transport.
+#[derive(Debug, Clone)]
+pub struct ReqwestTransport(reqwest::Client);
+
+impl ReqwestTransport {
+    /// Make a new [`ReqwestTransport`] that sends requests with the given client.
+    pub fn new(client: reqwest::Client) -> Self {
+        Self(client)
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn execute(&self, request: reqwest::Request) -> TransportFuture<'_> {
+        Box::pin(async move { Ok(self.0.execute(request).await?) })
+    }
+}
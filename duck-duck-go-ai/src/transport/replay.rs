@@ -0,0 +1,169 @@
+use super::Transport;
+use super::TransportFuture;
+use crate::Error;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// A recorded http response.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct RecordedResponse {
+    /// The status code
+    status: u16,
+
+    /// The headers
+    headers: Vec<(String, String)>,
+
+    /// The body
+    body: String,
+}
+
+impl RecordedResponse {
+    /// Convert this into a reqwest response.
+    fn into_response(self) -> Result<reqwest::Response, Error> {
+        let mut builder = http::Response::builder().status(self.status);
+        for (name, value) in self.headers {
+            builder = builder.header(name, value);
+        }
+        let response = builder
+            .body(self.body)
+            .map_err(|error| Error::Io(std::io::Error::other(error)))?;
+
+        Ok(reqwest::Response::from(response))
+    }
+}
+
+/// Numbers exchanges by the last path segment of their url, like "status-0" and "chat-1".
+#[derive(Debug, Default)]
+struct ExchangeCounter(Mutex<HashMap<String, usize>>);
+
+impl ExchangeCounter {
+    /// Get the file name for the next exchange for a request.
+    fn next_file_name(&self, request: &reqwest::Request) -> String {
+        let kind = request
+            .url()
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|kind| !kind.is_empty())
+            .unwrap_or("root")
+            .to_string();
+
+        let mut counters = self.0.lock().unwrap_or_else(|error| error.into_inner());
+        let index = counters.entry(kind.clone()).or_default();
+        let file_name = format!("{kind}-{index}.json");
+        *index += 1;
+
+        file_name
+    }
+}
+
+/// A transport that saves every response to a directory.
+///
+/// Response bodies are read completely before they are returned,
+/// so responses are not streamed while recording.
+#[derive(Debug)]
+pub(crate) struct RecordingTransport {
+    inner: Arc<dyn Transport>,
+    dir: PathBuf,
+    counter: ExchangeCounter,
+}
+
+impl RecordingTransport {
+    /// Make a new [`RecordingTransport`] that records the responses of another transport.
+    pub(crate) fn new(inner: Arc<dyn Transport>, dir: PathBuf) -> Self {
+        Self {
+            inner,
+            dir,
+            counter: ExchangeCounter::default(),
+        }
+    }
+}
+
+impl RecordingTransport {
+    /// Send a request with the given function and record the response.
+    fn record<'a>(
+        &'a self,
+        request: reqwest::Request,
+        send: impl FnOnce(reqwest::Request) -> TransportFuture<'a> + Send + 'a,
+    ) -> TransportFuture<'a> {
+        Box::pin(async move {
+            let file_name = self.counter.next_file_name(&request);
+            let response = send(request).await?;
+
+            let status = response.status().as_u16();
+            let headers = response
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    let value = value.to_str().ok()?;
+                    Some((name.to_string(), value.to_string()))
+                })
+                .collect();
+            let body = response.text().await?;
+            let recorded = RecordedResponse {
+                status,
+                headers,
+                body,
+            };
+
+            let json = serde_json::to_vec_pretty(&recorded)
+                .map_err(|error| Error::Io(std::io::Error::other(error)))?;
+            tokio::fs::create_dir_all(&self.dir).await?;
+            tokio::fs::write(self.dir.join(file_name), json).await?;
+
+            recorded.into_response()
+        })
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn execute(&self, request: reqwest::Request) -> TransportFuture<'_> {
+        self.record(request, |request| self.inner.execute(request))
+    }
+
+    fn status(&self, request: reqwest::Request) -> TransportFuture<'_> {
+        self.record(request, |request| self.inner.status(request))
+    }
+
+    fn chat(&self, request: reqwest::Request) -> TransportFuture<'_> {
+        self.record(request, |request| self.inner.chat(request))
+    }
+}
+
+/// A transport that serves responses recorded by a [`RecordingTransport`].
+///
+/// Responses for each url path are served in the order they were recorded.
+#[derive(Debug)]
+pub(crate) struct ReplayTransport {
+    dir: PathBuf,
+    counter: ExchangeCounter,
+}
+
+impl ReplayTransport {
+    /// Make a new [`ReplayTransport`] that serves responses from a directory.
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            counter: ExchangeCounter::default(),
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn execute(&self, request: reqwest::Request) -> TransportFuture<'_> {
+        Box::pin(async move {
+            let path = self.dir.join(self.counter.next_file_name(&request));
+            let json = tokio::fs::read(&path).await.map_err(|error| {
+                std::io::Error::new(
+                    error.kind(),
+                    format!("no recorded response at \"{}\"", path.display()),
+                )
+            })?;
+            let recorded: RecordedResponse = serde_json::from_slice(&json)
+                .map_err(|error| Error::Io(std::io::Error::other(error)))?;
+
+            recorded.into_response()
+        })
+    }
+}